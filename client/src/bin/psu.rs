@@ -0,0 +1,173 @@
+/*!
+  Command line client for the remote control port.
+
+  ```text
+  psu [--port <device>] <command>
+
+  idn                 Identify the supply
+  status              Show the setpoints and the output mode
+  set <volts>         Set the voltage
+  current <amps>      Set the current limit
+  on, off             Turn the output on or off
+  measure [--watch]   Measure the output voltage, every half second with --watch
+  ```
+
+  The port defaults to `$PSU_PORT`, or `/dev/ttyUSB0` if that is not set. It
+  is opened as a plain file, so configure it first, for example with
+  `stty -F /dev/ttyUSB0 115200 raw -echo`.
+*/
+extern crate client;
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use client::{Mode, Psu};
+
+const DEFAULT_PORT: &str = "/dev/ttyUSB0";
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+const USAGE: &str = "Usage: psu [--port <device>] <command>
+
+Commands:
+  idn                 Identify the supply
+  status              Show the setpoints and the output mode
+  set <volts>         Set the voltage
+  current <amps>      Set the current limit
+  on, off             Turn the output on or off
+  measure [--watch]   Measure the output voltage, every half second with --watch";
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Identify,
+    Status,
+    SetVoltage(f32),
+    SetCurrent(f32),
+    Output(bool),
+    Measure { watch: bool },
+}
+
+/// Parses the arguments after the program name into the port and the action
+fn parse_args(args: &[String]) -> Result<(Option<String>, Action), String> {
+    let mut port = None;
+    let mut words = Vec::new();
+    let mut watch = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => port = Some(args.next().ok_or("--port needs a device")?.clone()),
+            "--watch" => watch = true,
+            _ => words.push(arg.as_str()),
+        }
+    }
+
+    let number = |value: Option<&&str>| -> Result<f32, String> {
+        let value = value.ok_or("Missing value")?;
+        value.parse().map_err(|_| format!("{} is not a number", value))
+    };
+    let action = match words.first() {
+        Some(&"idn") => Action::Identify,
+        Some(&"status") => Action::Status,
+        Some(&"set") => Action::SetVoltage(number(words.get(1))?),
+        Some(&"current") => Action::SetCurrent(number(words.get(1))?),
+        Some(&"on") => Action::Output(true),
+        Some(&"off") => Action::Output(false),
+        Some(&"measure") => Action::Measure { watch },
+        Some(other) => return Err(format!("Unknown command {}", other)),
+        None => return Err("Missing command".to_string()),
+    };
+    Ok((port, action))
+}
+
+fn run(psu: &mut Psu<File>, action: Action) -> client::Result<()> {
+    match action {
+        Action::Identify => println!("{}", psu.identify()?),
+        Action::Status => {
+            let output = match psu.mode()? {
+                Mode::Off => "off",
+                Mode::ConstantVoltage => "CV",
+                Mode::ConstantCurrent => "CC",
+            };
+            println!(
+                "{:.3} V  {:.3} A  output {}",
+                psu.voltage()?,
+                psu.current_limit()?,
+                output
+            );
+        }
+        Action::SetVoltage(volts) => psu.set_voltage(volts)?,
+        Action::SetCurrent(amps) => psu.set_current_limit(amps)?,
+        Action::Output(on) => psu.set_output(on)?,
+        Action::Measure { watch: false } => println!("{:.3} V", psu.measure_voltage()?),
+        Action::Measure { watch: true } => loop {
+            println!("{:.3} V", psu.measure_voltage()?);
+            thread::sleep(WATCH_INTERVAL);
+        },
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let (port, action) = match parse_args(&args) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let port = port
+        .or_else(|| env::var("PSU_PORT").ok())
+        .unwrap_or_else(|| DEFAULT_PORT.to_string());
+    let file = match OpenOptions::new().read(true).write(true).open(&port) {
+        Ok(file) => file,
+        Err(error) => {
+            eprintln!("Failed to open {}: {}", port, error);
+            process::exit(1);
+        }
+    };
+
+    if let Err(error) = run(&mut Psu::new(file), action) {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<(Option<String>, Action), String> {
+        parse_args(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse(&["set", "5.0"]), Ok((None, Action::SetVoltage(5.))));
+        assert_eq!(parse(&["current", "0.5"]), Ok((None, Action::SetCurrent(0.5))));
+        assert_eq!(parse(&["on"]), Ok((None, Action::Output(true))));
+        assert_eq!(parse(&["measure"]), Ok((None, Action::Measure { watch: false })));
+        assert_eq!(parse(&["measure", "--watch"]), Ok((None, Action::Measure { watch: true })));
+    }
+
+    #[test]
+    fn port() {
+        assert_eq!(
+            parse(&["--port", "/dev/ttyACM0", "off"]),
+            Ok((Some("/dev/ttyACM0".to_string()), Action::Output(false)))
+        );
+        assert!(parse(&["off", "--port"]).is_err());
+    }
+
+    #[test]
+    fn bad_arguments() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["set"]).is_err());
+        assert!(parse(&["set", "five"]).is_err());
+        assert!(parse(&["explode"]).is_err());
+    }
+}
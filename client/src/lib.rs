@@ -0,0 +1,402 @@
+/*!
+  Host side client for the remote control port of the supply.
+
+  `Psu` speaks the SCPI subset implemented by `logic::remote` over anything
+  that is `Read + Write`, usually a serial port opened as a file. Settings
+  are followed by `SYST:ERR?` so that a rejected setting is reported as an
+  error instead of being lost.
+*/
+
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The supply replied with something that could not be understood
+    Protocol(String),
+    /// The supply rejected a command
+    Remote { code: i16, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref error) => write!(f, "{}", error),
+            Error::Protocol(ref reply) => write!(f, "Unexpected reply {:?}", reply),
+            Error::Remote { code, ref message } => write!(f, "{} ({})", message, code),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// What the output is regulating
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Off,
+    ConstantVoltage,
+    ConstantCurrent,
+}
+
+/// A supply connected through `port`
+pub struct Psu<T: Read + Write> {
+    port: T,
+}
+
+impl<T: Read + Write> Psu<T> {
+    pub fn new(port: T) -> Self {
+        Self { port }
+    }
+
+    /// Gives back the port
+    pub fn into_inner(self) -> T {
+        self.port
+    }
+
+    /// Manufacturer, model, serial number and firmware version
+    pub fn identify(&mut self) -> Result<String> {
+        self.query("*IDN?")
+    }
+
+    pub fn set_voltage(&mut self, volts: f32) -> Result<()> {
+        self.set(&format!("VOLT {:.3}", volts))
+    }
+
+    /// The voltage setpoint
+    pub fn voltage(&mut self) -> Result<f32> {
+        self.query_number("VOLT?")
+    }
+
+    pub fn set_current_limit(&mut self, amps: f32) -> Result<()> {
+        self.set(&format!("CURR {:.3}", amps))
+    }
+
+    pub fn current_limit(&mut self) -> Result<f32> {
+        self.query_number("CURR?")
+    }
+
+    /**
+      Turns the output on or off. The output switch on the front panel must
+      also be on for the output to be live
+    */
+    pub fn set_output(&mut self, on: bool) -> Result<()> {
+        self.set(if on { "OUTP ON" } else { "OUTP OFF" })
+    }
+
+    /// True if the output is live
+    pub fn output(&mut self) -> Result<bool> {
+        self.query_bool("OUTP?")
+    }
+
+    /// The measured output voltage
+    pub fn measure_voltage(&mut self) -> Result<f32> {
+        self.query_number("MEAS:VOLT?")
+    }
+
+    /// Whether the output holds the voltage or the current
+    pub fn mode(&mut self) -> Result<Mode> {
+        match self.query("OUTP:MODE?")?.as_str() {
+            "OFF" => Ok(Mode::Off),
+            "CV" => Ok(Mode::ConstantVoltage),
+            "CC" => Ok(Mode::ConstantCurrent),
+            reply => Err(Error::Protocol(reply.to_string())),
+        }
+    }
+
+    /// How long the current may exceed the limit before the output trips
+    pub fn set_over_current_delay(&mut self, seconds: f32) -> Result<()> {
+        self.set(&format!("CURR:PROT:DEL {:.3}", seconds))
+    }
+
+    /// True if over-current protection has turned the output off
+    pub fn over_current_tripped(&mut self) -> Result<bool> {
+        self.query_bool("CURR:PROT:TRIP?")
+    }
+
+    /// The measured voltage above which the output trips
+    pub fn set_over_voltage(&mut self, volts: f32) -> Result<()> {
+        self.set(&format!("VOLT:PROT {:.3}", volts))
+    }
+
+    pub fn over_voltage(&mut self) -> Result<f32> {
+        self.query_number("VOLT:PROT?")
+    }
+
+    /// True if over-voltage protection has turned the output off
+    pub fn over_voltage_tripped(&mut self) -> Result<bool> {
+        self.query_bool("VOLT:PROT:TRIP?")
+    }
+
+    /// How fast the output moves to a new voltage, in V/ms
+    pub fn set_slew_rate(&mut self, volts_per_ms: f32) -> Result<()> {
+        self.set(&format!("VOLT:SLEW {:.3}", volts_per_ms))
+    }
+
+    pub fn slew_rate(&mut self) -> Result<f32> {
+        self.query_number("VOLT:SLEW?")
+    }
+
+    /// Clears a tripped protection so the output can come back on
+    pub fn reset_protection(&mut self) -> Result<()> {
+        self.set("OUTP:PROT:CLE")
+    }
+
+    /// Removes the oldest error from the queue on the supply
+    pub fn next_error(&mut self) -> Result<Option<Error>> {
+        let reply = self.query("SYST:ERR?")?;
+        let parsed = reply.find(',').and_then(|split| {
+            let code = reply[..split].parse::<i16>().ok()?;
+            let message = reply[split + 1..].trim_matches('"').to_string();
+            Some((code, message))
+        });
+        match parsed {
+            Some((0, _)) => Ok(None),
+            Some((code, message)) => Ok(Some(Error::Remote { code, message })),
+            None => Err(Error::Protocol(reply)),
+        }
+    }
+
+    /// Sends a setting and reports the error if the supply rejected it
+    fn set(&mut self, command: &str) -> Result<()> {
+        self.send(command)?;
+        match self.next_error()? {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    fn query_number(&mut self, query: &str) -> Result<f32> {
+        let reply = self.query(query)?;
+        reply.parse().map_err(|_| Error::Protocol(reply))
+    }
+
+    fn query_bool(&mut self, query: &str) -> Result<bool> {
+        match self.query(query)?.as_str() {
+            "1" => Ok(true),
+            "0" => Ok(false),
+            reply => Err(Error::Protocol(reply.to_string())),
+        }
+    }
+
+    fn query(&mut self, query: &str) -> Result<String> {
+        self.send(query)?;
+        self.read_line()
+    }
+
+    fn send(&mut self, line: &str) -> Result<()> {
+        self.port.write_all(line.as_bytes())?;
+        self.port.write_all(b"\n")?;
+        self.port.flush()?;
+        Ok(())
+    }
+
+    /// Reads one line, without the line ending
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        loop {
+            if self.port.read(&mut byte)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The supply stopped replying").into());
+            }
+            match byte[0] {
+                b'\n' => break,
+                b'\r' => {}
+                other => line.push(other),
+            }
+        }
+        String::from_utf8(line).map_err(|error| {
+            Error::Protocol(String::from_utf8_lossy(error.as_bytes()).into_owned())
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate logic;
+
+    use std::collections::VecDeque;
+
+    use self::logic::remote::{Remote, Response};
+    use self::logic::state::State;
+
+    /// Runs the firmware command handler in process
+    struct Emulator {
+        remote: Remote,
+        state: State,
+        replies: VecDeque<u8>,
+    }
+
+    impl Emulator {
+        fn new() -> Self {
+            let mut state = State::new(false);
+            // Output switch on the front panel
+            state.set_output_switch_state(true);
+            Self { remote: Remote::new(), state, replies: VecDeque::new() }
+        }
+    }
+
+    impl Write for Emulator {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            for &byte in buf {
+                let mut response = Response::new();
+                if let Some(command) = self.remote.receive(byte, &self.state, &mut response) {
+                    self.state.apply_command(command);
+                }
+                self.replies.extend(response.bytes());
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for Emulator {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let amount = buf.len().min(self.replies.len());
+            for (target, byte) in buf.iter_mut().zip(self.replies.drain(..amount)) {
+                *target = byte;
+            }
+            Ok(amount)
+        }
+    }
+
+    fn psu() -> Psu<Emulator> {
+        Psu::new(Emulator::new())
+    }
+
+    #[test]
+    fn identify() {
+        assert!(psu().identify().unwrap().starts_with("TheZoq2,PSU,"));
+    }
+
+    #[test]
+    fn setpoints() {
+        let mut psu = psu();
+        psu.set_voltage(5.).unwrap();
+        psu.set_current_limit(0.25).unwrap();
+        assert_eq!(psu.voltage().unwrap(), 5.);
+        assert_eq!(psu.current_limit().unwrap(), 0.25);
+        assert_eq!(psu.into_inner().state.get_display().unwrap().as_str(), "5.00V 0.25A CV");
+    }
+
+    #[test]
+    fn output() {
+        let mut psu = psu();
+        assert!(psu.output().unwrap());
+        psu.set_output(false).unwrap();
+        assert!(!psu.output().unwrap());
+        psu.set_output(true).unwrap();
+        assert!(psu.output().unwrap());
+    }
+
+    #[test]
+    fn measure() {
+        let mut emulator = Emulator::new();
+        emulator.state.set_filtered_voltage(4.9876);
+        assert_eq!(Psu::new(emulator).measure_voltage().unwrap(), 4.988);
+    }
+
+    #[test]
+    fn mode() {
+        let mut psu = psu();
+        psu.set_current_limit(1.).unwrap();
+        assert_eq!(psu.mode().unwrap(), Mode::ConstantVoltage);
+        let mut emulator = psu.into_inner();
+        emulator.state.set_measured_current(1.);
+        emulator.state.update_mode();
+
+        let mut psu = Psu::new(emulator);
+        assert_eq!(psu.mode().unwrap(), Mode::ConstantCurrent);
+        psu.set_output(false).unwrap();
+        assert_eq!(psu.mode().unwrap(), Mode::Off);
+    }
+
+    #[test]
+    fn over_current_protection() {
+        let mut psu = psu();
+        psu.set_current_limit(0.5).unwrap();
+        psu.set_over_current_delay(0.001).unwrap();
+        let mut emulator = psu.into_inner();
+        emulator.state.set_measured_current(1.);
+        emulator.state.update_protection(1);
+
+        let mut psu = Psu::new(emulator);
+        assert!(psu.over_current_tripped().unwrap());
+        assert!(!psu.output().unwrap());
+        psu.reset_protection().unwrap();
+        assert!(!psu.over_current_tripped().unwrap());
+        // Resetting doesn't turn the output back on by itself
+        assert!(!psu.output().unwrap());
+        psu.set_output(true).unwrap();
+        assert!(psu.output().unwrap());
+    }
+
+    #[test]
+    fn over_voltage_protection() {
+        let mut psu = psu();
+        psu.set_over_voltage(6.).unwrap();
+        assert_eq!(psu.over_voltage().unwrap(), 6.);
+        let mut emulator = psu.into_inner();
+        emulator.state.set_measured_voltage(7.);
+        emulator.state.update_protection(1);
+
+        let mut psu = Psu::new(emulator);
+        assert!(psu.over_voltage_tripped().unwrap());
+        assert!(!psu.over_current_tripped().unwrap());
+    }
+
+    #[test]
+    fn slew_rate() {
+        let mut psu = psu();
+        psu.set_slew_rate(0.05).unwrap();
+        assert_eq!(psu.slew_rate().unwrap(), 0.05);
+        assert!(psu.set_slew_rate(0.).is_err());
+    }
+
+    #[test]
+    fn rejected_settings_are_errors() {
+        let mut psu = psu();
+        match psu.set_voltage(30.) {
+            Err(Error::Remote { code: -222, ref message }) if message == "Data out of range" => {}
+            other => panic!("{:?}", other),
+        }
+        // The error was read from the queue
+        assert!(psu.next_error().unwrap().is_none());
+        assert_eq!(psu.voltage().unwrap(), 1.291);
+    }
+
+    #[test]
+    fn missing_reply_is_an_error() {
+        let mut psu = Psu::new(io::Cursor::new(Vec::new()));
+        match psu.voltage() {
+            Err(Error::Io(ref error)) if error.kind() == io::ErrorKind::UnexpectedEof => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn garbled_reply_is_an_error() {
+        let mut emulator = Emulator::new();
+        emulator.replies.extend(b"five\n");
+        let mut psu = Psu::new(emulator);
+        match psu.measure_voltage() {
+            Err(Error::Protocol(ref reply)) if reply == "five" => {}
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -2,7 +2,8 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  /* The last 2K of flash are used to store settings, see src/flash.rs */
+FLASH : ORIGIN = 0x08000000, LENGTH = 62K
   RAM : ORIGIN = 0x20000000, LENGTH = 20K
 }
 
@@ -0,0 +1,55 @@
+use stm32f103xx::{ADC1, RCC};
+
+use logic::adc::Sampler;
+
+/**
+  Minimal blocking driver for single conversions on ADC1, one channel at a
+  time
+*/
+pub struct Adc {
+    adc: ADC1,
+}
+
+impl Adc {
+    /**
+      Powers up and calibrates ADC1 and selects `channel` as the only channel
+      in the regular sequence. The pins have to be left in their reset state
+      (floating input) for the ADC to see the voltage on them.
+    */
+    pub fn new(adc: ADC1, channel: u8) -> Self {
+        // The HAL doesn't expose the ADC so the clock has to be enabled by hand
+        unsafe {
+            (*RCC::ptr()).apb2enr.modify(|_, w| w.adc1en().set_bit());
+        }
+
+        adc.sqr1.modify(|_, w| unsafe { w.l().bits(0) });
+
+        adc.cr2.modify(|_, w| w.adon().set_bit());
+        adc.cr2.modify(|_, w| w.rstcal().set_bit());
+        while adc.cr2.read().rstcal().bit_is_set() {}
+        adc.cr2.modify(|_, w| w.cal().set_bit());
+        while adc.cr2.read().cal().bit_is_set() {}
+
+        let mut result = Self { adc };
+        result.select(channel);
+        result
+    }
+
+    /// Converts `channel`, one of 0 to 9, from now on
+    pub fn select(&mut self, channel: u8) {
+        // Longest sample time, the sense circuits have a high impedance
+        self.adc.smpr2.modify(|r, w| unsafe {
+            w.bits(r.bits() | 0b111 << (3 * channel as u32))
+        });
+        self.adc.sqr3.modify(|_, w| unsafe { w.sq1().bits(channel) });
+    }
+}
+
+impl Sampler for Adc {
+    fn sample(&mut self) -> u16 {
+        // Setting ADON while the ADC is on starts a conversion
+        self.adc.cr2.modify(|_, w| w.adon().set_bit());
+        while self.adc.sr.read().eoc().bit_is_clear() {}
+        self.adc.dr.read().data().bits()
+    }
+}
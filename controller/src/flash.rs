@@ -0,0 +1,120 @@
+use core::ptr;
+
+use stm32f103xx::FLASH;
+
+use logic::persistence::Storage;
+
+/// Start of the flash pages reserved for settings, see memory.x
+const START_ADDRESS: usize = 0x0800_f800;
+const PAGE_SIZE: usize = 1024;
+const PAGE_COUNT: usize = 2;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xcdef_89ab;
+
+#[derive(Debug)]
+pub enum Error {
+    OutOfBounds,
+    /// The flash controller reported a programming or write protection error
+    Programming,
+}
+
+/**
+  Settings storage in the last pages of the internal flash
+*/
+pub struct FlashStorage;
+
+impl FlashStorage {
+    pub fn new() -> Self {
+        FlashStorage
+    }
+
+    fn check_bounds(&self, address: usize, len: usize) -> Result<(), Error> {
+        if address + len > PAGE_SIZE * PAGE_COUNT {
+            Err(Error::OutOfBounds)
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    /**
+      Unlocks the flash controller, runs `operation` and locks it again. The
+      HAL only gives access to ACR so the registers are accessed directly.
+    */
+    fn unlocked<F>(&mut self, operation: F) -> Result<(), Error>
+        where F: FnOnce(&stm32f103xx::flash::RegisterBlock)
+    {
+        let flash = unsafe { &*FLASH::ptr() };
+        if flash.cr.read().lock().bit_is_set() {
+            flash.keyr.write(|w| unsafe { w.key().bits(KEY1) });
+            flash.keyr.write(|w| unsafe { w.key().bits(KEY2) });
+        }
+
+        operation(flash);
+        while flash.sr.read().bsy().bit_is_set() {}
+
+        let status = flash.sr.read();
+        let failed = status.pgerr().bit_is_set() || status.wrprterr().bit_is_set();
+        // Clear the status flags by writing ones to them
+        flash.sr.modify(|_, w| w.pgerr().set_bit().wrprterr().set_bit().eop().set_bit());
+        flash.cr.modify(|_, w| w.lock().set_bit());
+
+        if failed {
+            Err(Error::Programming)
+        }
+        else {
+            Ok(())
+        }
+    }
+}
+
+impl Storage for FlashStorage {
+    type Error = Error;
+
+    fn page_size(&self) -> usize {
+        PAGE_SIZE
+    }
+
+    fn page_count(&self) -> usize {
+        PAGE_COUNT
+    }
+
+    fn read(&mut self, address: usize, buffer: &mut [u8]) -> Result<(), Error> {
+        self.check_bounds(address, buffer.len())?;
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = unsafe { ptr::read_volatile((START_ADDRESS + address + i) as *const u8) };
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, address: usize, data: &[u8]) -> Result<(), Error> {
+        self.check_bounds(address, data.len())?;
+        // The flash is programmed one half word at a time
+        for (i, chunk) in data.chunks(2).enumerate() {
+            let half_word = chunk[0] as u16 | (*chunk.get(1).unwrap_or(&0xff) as u16) << 8;
+            let target = (START_ADDRESS + address + i * 2) as *mut u16;
+            self.unlocked(|flash| {
+                flash.cr.modify(|_, w| w.pg().set_bit());
+                unsafe { ptr::write_volatile(target, half_word) };
+                while flash.sr.read().bsy().bit_is_set() {}
+                flash.cr.modify(|_, w| w.pg().clear_bit());
+            })?;
+        }
+        Ok(())
+    }
+
+    fn erase_page(&mut self, page: usize) -> Result<(), Error> {
+        if page >= PAGE_COUNT {
+            return Err(Error::OutOfBounds);
+        }
+        let address = (START_ADDRESS + page * PAGE_SIZE) as u32;
+        self.unlocked(|flash| {
+            flash.cr.modify(|_, w| w.per().set_bit());
+            flash.ar.write(|w| unsafe { w.far().bits(address) });
+            flash.cr.modify(|_, w| w.strt().set_bit());
+            while flash.sr.read().bsy().bit_is_set() {}
+            flash.cr.modify(|_, w| w.per().clear_bit());
+        })
+    }
+}
@@ -15,12 +15,19 @@ extern crate itoa;
 extern crate arrayvec;
 #[macro_use]
 extern crate nb;
+extern crate logic;
 
-mod voltage;
-mod keypad;
-mod keymap;
-mod interface;
-mod state;
+mod adc;
+mod flash;
+
+use logic::{voltage, current, keypad, keymap, interface, debounce};
+use logic::scanner::{EventQueue, Scanner, Consumer};
+use logic::menu::Menu;
+use logic::control::{self, PiController};
+use logic::adc::{self as adc_conversion, Sampler};
+use logic::filter::{self, Filter};
+use logic::persistence::{DelayedSave, Persistence};
+use logic::remote::{self, OutputQueue, Remote};
 
 use rtfm::{Threshold, app};
 
@@ -29,15 +36,16 @@ use stm32f103xx_hal::prelude::*;
 use stm32f103xx_hal::gpio::gpioa::{PA8, self};
 use stm32f103xx_hal::gpio::gpiob::{PBx, self};
 use stm32f103xx_hal::gpio::{Output, PushPull, Input, PullDown, PullUp};
-use stm32f103xx_hal::timer::{Timer};
+use stm32f103xx_hal::timer::{Timer, Event};
 use stm32f103xx_hal::pwm;
+use stm32f103xx_hal::serial::{self, Serial};
 use stm32f103xx_hal::time::Hertz;
-use stm32f103xx::{TIM3};
+use stm32f103xx::{TIM3, TIM4, USART3};
 use stm32f103xx::{EXTI, NVIC};
 use rt::ExceptionFrame;
 use rtfm::Resource;
 
-use state::State;
+use logic::state::State;
 
 
 type Lcd = hd44780_driver::HD44780<
@@ -59,6 +67,29 @@ type Lcd = hd44780_driver::HD44780<
     >,
 >;
 
+/// How often the keypad is scanned in milliseconds
+const KEY_SCAN_PERIOD_MS: u32 = 5;
+/// Debouncing and long press timing of the keypad. Keys do not repeat
+const KEY_TIMING: debounce::Timing = debounce::Timing {
+    debounce: 20,
+    long_press: 500,
+    repeat_delay: None,
+    repeat_interval: 0,
+};
+
+/// How often the output voltage is measured and the duty corrected
+const CONTROL_FREQUENCY: u32 = 1_000;
+/// The voltage sense divider is connected to PA2
+const VOLTAGE_CHANNEL: u8 = 2;
+/// The current sense amplifier is connected to PA3
+const CURRENT_CHANNEL: u8 = 3;
+
+/// Baud rate of the remote control port
+const REMOTE_BAUD_RATE: u32 = 115_200;
+
+/// Key events from `scan_keypad` to `idle`
+static mut KEY_QUEUE: EventQueue = EventQueue::new();
+
 type KeypadInput = PBx<Input<PullDown>>;
 type KeypadOutput = PBx<Output<PushPull>>;
 type Keypad = keypad::Keypad<[KeypadInput; 3], [KeypadOutput; 4], KeypadInput, KeypadOutput>;
@@ -69,28 +100,68 @@ app! {
 
     resources: {
         static PWM: pwm::Pwm<stm32f103xx::TIM2, pwm::C1>;
+        static CURRENT_PWM: pwm::Pwm<stm32f103xx::TIM2, pwm::C2>;
         static LCD: Lcd;
         static KEYPAD: Keypad;
-        static KEY_DELAY_TIMER: Timer<TIM3>;
+        static KEY_SCAN_TIMER: Timer<TIM3>;
+        static KEY_SCANNER: Scanner<'static>;
+        static KEY_EVENTS: Consumer<'static>;
         static OUTPUT_SENSOR: PA8<Input<PullUp>>;
         static STATE: State;
         static INTERRUPT_CONTROLLER: NVIC;
         static EXTI_CONTROLLER: EXTI;
+        static ADC: adc::Adc;
+        static CONTROL_TIMER: Timer<TIM4>;
+        static VOLTAGE_CONTROLLER: PiController;
+        static VOLTAGE_FILTER: filter::Measurement;
+        static CURRENT_FILTER: filter::Measurement;
+        static PERSISTENCE: Persistence<flash::FlashStorage>;
+        static SERIAL_RX: serial::Rx<USART3>;
+        static SERIAL_TX: serial::Tx<USART3>;
+        static REMOTE: Remote;
+        static REMOTE_OUTPUT: OutputQueue;
+        // Milliseconds since power up, counted by the control loop
+        static UPTIME: u32 = 0;
     },
 
     idle: {
-        resources: [KEYPAD, KEY_DELAY_TIMER, STATE, LCD, INTERRUPT_CONTROLLER]
+        resources: [KEY_EVENTS, STATE, LCD, INTERRUPT_CONTROLLER, PERSISTENCE, UPTIME]
     },
 
     tasks: {
         EXTI1: {
             path: state_changed,
-            resources: [PWM, LCD, STATE]
+            resources: [CURRENT_PWM, LCD, STATE]
         },
 
         EXTI9_5: {
             path: output_switch_changed,
             resources: [OUTPUT_SENSOR, INTERRUPT_CONTROLLER, STATE, EXTI_CONTROLLER]
+        },
+
+        TIM3: {
+            path: scan_keypad,
+            resources: [KEY_SCAN_TIMER, KEYPAD, KEY_SCANNER]
+        },
+
+        TIM4: {
+            path: control_loop,
+            resources: [
+                CONTROL_TIMER,
+                ADC,
+                PWM,
+                STATE,
+                VOLTAGE_CONTROLLER,
+                VOLTAGE_FILTER,
+                CURRENT_FILTER,
+                INTERRUPT_CONTROLLER,
+                UPTIME
+            ]
+        },
+
+        USART3: {
+            path: remote_serial,
+            resources: [SERIAL_RX, SERIAL_TX, REMOTE, REMOTE_OUTPUT, STATE, INTERRUPT_CONTROLLER]
         }
     }
 }
@@ -108,16 +179,55 @@ fn init(p: init::Peripherals) -> init::LateResources {
     // Disable the JTAG hardware to free up PB3 and 4
     afio.mapr.disable_jtag();
 
-    // Timer used for preventing bouncy buttons
-    let timer = Timer::tim3(p.device.TIM3, Hertz(100), clocks, &mut rcc.apb1);
+    // Timer triggering the keypad scans
+    let mut key_scan_timer = Timer::tim3(
+        p.device.TIM3,
+        Hertz(1_000 / KEY_SCAN_PERIOD_MS),
+        clocks,
+        &mut rcc.apb1
+    );
+    key_scan_timer.listen(Event::Update);
 
     ////////////////////////////////////////////////////////////////////////////////
     //                              PWM
     ////////////////////////////////////////////////////////////////////////////////
-    let pwm_pin = gpioa.pa0.into_alternate_push_pull(&mut gpioa.crl);
-    let mut pwm = p.device.TIM2.pwm(pwm_pin, &mut afio.mapr, Hertz(10_000), clocks, &mut rcc.apb1);
+    // Channel 1 sets the output voltage, channel 2 sets the current limit
+    let pwm_pins = (
+        gpioa.pa0.into_alternate_push_pull(&mut gpioa.crl),
+        gpioa.pa1.into_alternate_push_pull(&mut gpioa.crl),
+    );
+    let (mut pwm, mut current_pwm) = p.device.TIM2.pwm(
+        pwm_pins,
+        &mut afio.mapr,
+        Hertz(10_000),
+        clocks,
+        &mut rcc.apb1
+    );
     pwm.set_duty(0);
     pwm.enable();
+    current_pwm.set_duty(0);
+    current_pwm.enable();
+
+    ////////////////////////////////////////////////////////////////////////////////
+    //                          Voltage feedback
+    ////////////////////////////////////////////////////////////////////////////////
+    let adc = adc::Adc::new(p.device.ADC1, VOLTAGE_CHANNEL);
+
+    let voltage_controller = PiController::new(
+        control::VOLTAGE_KP,
+        control::VOLTAGE_KI,
+        1. / (CONTROL_FREQUENCY as f32),
+        0.,
+        1.
+    );
+
+    let mut control_timer = Timer::tim4(
+        p.device.TIM4,
+        Hertz(CONTROL_FREQUENCY),
+        clocks,
+        &mut rcc.apb1
+    );
+    control_timer.listen(Event::Update);
 
     ////////////////////////////////////////////////////////////////////////////////
     //                              LCD
@@ -173,31 +283,72 @@ fn init(p: init::Peripherals) -> init::LateResources {
     p.device.EXTI.rtsr.modify(|_r, w| w.tr8().set_bit());
     p.device.EXTI.ftsr.modify(|_r, w| w.tr8().set_bit());
 
+    ////////////////////////////////////////////////////////////////////////////////
+    //                          Remote control
+    ////////////////////////////////////////////////////////////////////////////////
+    // USART1 and USART2 share pins with the LCD, keypad and ADC
+    let serial_pins = (
+        gpiob.pb10.into_alternate_push_pull(&mut gpiob.crh),
+        gpiob.pb11,
+    );
+    let mut serial = Serial::usart3(
+        p.device.USART3,
+        serial_pins,
+        &mut afio.mapr,
+        REMOTE_BAUD_RATE.bps(),
+        clocks,
+        &mut rcc.apb1
+    );
+    serial.listen(serial::Event::Rxne);
+    let (serial_tx, serial_rx) = serial.split();
+
     ////////////////////////////////////////////////////////////////////////////////
     //                          Other
     ////////////////////////////////////////////////////////////////////////////////
-    let state = State::new(output_sensor.is_low());
+    let mut state = State::new(output_sensor.is_low());
+
+    // Restore the settings from before the last power cycle
+    let mut persistence = Persistence::new(flash::FlashStorage::new());
+    if let Ok(Some(settings)) = persistence.load() {
+        state.apply_settings(settings);
+    }
 
 
     // Write the initial state to the LCD
-    write_line(1, &mut lcd, &state.get_display().unwrap());
+    write_line(1, &mut lcd, &state.status_line().unwrap());
+
+    // Init runs once so this is the only reference to the queue
+    let (key_producer, key_consumer) = unsafe { KEY_QUEUE.split() };
 
     init::LateResources {
         PWM: pwm,
+        CURRENT_PWM: current_pwm,
         LCD: lcd,
         KEYPAD: keypad,
-        KEY_DELAY_TIMER: timer,
+        KEY_SCAN_TIMER: key_scan_timer,
+        KEY_SCANNER: Scanner::new(KEY_TIMING, KEY_SCAN_PERIOD_MS, key_producer),
+        KEY_EVENTS: key_consumer,
         OUTPUT_SENSOR: output_sensor,
         STATE: state,
         INTERRUPT_CONTROLLER: p.core.NVIC,
         EXTI_CONTROLLER: p.device.EXTI,
+        ADC: adc,
+        CONTROL_TIMER: control_timer,
+        VOLTAGE_CONTROLLER: voltage_controller,
+        VOLTAGE_FILTER: filter::Measurement::new(1_000_000 / CONTROL_FREQUENCY),
+        CURRENT_FILTER: filter::Measurement::new(1_000_000 / CONTROL_FREQUENCY),
+        PERSISTENCE: persistence,
+        SERIAL_RX: serial_rx,
+        SERIAL_TX: serial_tx,
+        REMOTE: Remote::new(),
+        REMOTE_OUTPUT: OutputQueue::new(),
     }
 }
 
 fn idle(t: &mut Threshold, mut r: idle::Resources) -> ! {
-    let mut last_key = None;
+    let mut delayed_save = DelayedSave::new(r.STATE.claim(t, |state, _t| state.settings()));
 
-    let mut interface_state = interface::State::Start;
+    let mut interface_state = Menu::new(&interface::MAIN_MENU);
     let message = interface_state.get_display().unwrap();
 
     r.LCD.claim_mut(t, |lcd, _t| {
@@ -205,64 +356,203 @@ fn idle(t: &mut Threshold, mut r: idle::Resources) -> ! {
     });
 
     loop {
-        let key = r.KEYPAD.read_first_key();
+        let event = match r.KEY_EVENTS.dequeue() {
+            Some(event) => event,
+            None => {
+                // Remote commands change the state from their interrupt, save
+                // what they changed here once it stops changing
+                let settings = r.STATE.claim(t, |state, _t| state.settings());
+                let now = r.UPTIME.claim(t, |uptime, _t| *uptime);
+                if let Some(settings) = delayed_save.poll(&settings, now) {
+                    r.PERSISTENCE.save(&settings).ok();
+                }
 
-        match key {
-            Some(coords) => {
-                let key_char = keypad::translate_coordinate(coords, &keymap::KEYMAP);
+                // Sleep until the next interrupt. An event queued just before
+                // this is handled after the next keypad scan
+                rtfm::wfi();
+                continue;
+            }
+        };
+
+        let input = keymap::event_char(&event, KEY_TIMING.long_press)
+            .and_then(|key_char| interface::DEFAULT_KEY_BINDINGS.input(key_char));
+        if let Some(input) = input {
+            // Process the key
+            let (new_state, command) = r.STATE.claim(t, |state, _t| {
+                interface_state.update(input, state)
+            });
+            interface_state = new_state;
+
+            if let Some(command) = command {
+                let settings = r.STATE.claim_mut(t, |state, _t| {
+                    state.apply_command(command);
+                    state.settings()
+                });
+
+                // Keypad changes are few and far between, so they are saved
+                // at once. If saving fails the settings are lost on the next
+                // power cycle but the supply keeps working
+                if !delayed_save.is_saved(&settings) {
+                    r.PERSISTENCE.save(&settings).ok();
+                    delayed_save.saved(settings);
+                }
+                r.INTERRUPT_CONTROLLER.claim_mut(t, |nvic, _t| {
+                    nvic.set_pending(stm32f103xx::Interrupt::EXTI1);
+                });
+            }
 
-                if Some(key_char) != last_key {
-                    // Process the key
-                    let (new_state, command) = interface_state.update(key_char);
-                    interface_state = new_state;
+            let message = interface_state.get_display().unwrap();
 
-                    if let Some(interface::Command::Voltage(val)) = command {
-                        r.STATE.claim_mut(t, |state, _t| {
-                            state.set_voltage(val);
-                        });
-                        r.INTERRUPT_CONTROLLER.claim_mut(t, |nvic, _t| {
-                            nvic.set_pending(stm32f103xx::Interrupt::EXTI1);
-                        });
-                    }
+            r.LCD.claim_mut(t, |lcd, _t| {
+                write_line(0, lcd, &message);
+            });
+        }
+    }
+}
 
-                    let message = interface_state.get_display().unwrap();
+/**
+  Scans the keypad and queues the key events for `idle`
+*/
+fn scan_keypad(_t: &mut Threshold, mut r: TIM3::Resources) {
+    // Clear the update flag
+    r.KEY_SCAN_TIMER.wait().ok();
 
-                    r.LCD.claim_mut(t, |lcd, _t| {
-                        write_line(0, lcd, &message);
-                    });
+    r.KEY_SCANNER.tick(&mut *r.KEYPAD);
+}
 
-                    last_key = Some(key_char)
-                }
+/**
+  Handles both directions of the remote control port. Received bytes are
+  collected into lines which are executed once complete.
 
-                r.KEY_DELAY_TIMER.start(Hertz(100));
-                block!(r.KEY_DELAY_TIMER.wait());
-            }
-            None => {
-                last_key = None
-            }
+  Responses are queued and sent one byte per transmit interrupt. Sending a
+  whole response here would keep the control loop waiting for several ms.
+*/
+fn remote_serial(_t: &mut Threshold, mut r: USART3::Resources) {
+    remote_receive(&mut r);
+
+    // Send the next byte if the transmitter is empty
+    if let Some(byte) = r.REMOTE_OUTPUT.front() {
+        if r.SERIAL_TX.write(byte).is_ok() {
+            r.REMOTE_OUTPUT.pop();
+        }
+    }
+    // Only interrupt on an empty transmitter while there is something to send.
+    // CR1 is only modified from here once init is done
+    let sending = !r.REMOTE_OUTPUT.is_empty();
+    unsafe { (*USART3::ptr()).cr1.modify(|_, w| w.txeie().bit(sending)) };
+}
+
+/// Reads a received byte, if any, and executes the line once it is complete
+fn remote_receive(r: &mut USART3::Resources) {
+    let byte = match r.SERIAL_RX.read() {
+        Ok(byte) => byte,
+        Err(nb::Error::WouldBlock) => return,
+        Err(nb::Error::Other(error)) => {
+            // The error flags are only cleared by reading the data register
+            // after the status register. Until then the interrupt fires again
+            // as soon as it returns
+            unsafe { (*USART3::ptr()).dr.read() };
+            // Bytes were lost or garbled, which breaks the line they were part of
+            r.REMOTE.discard_line(receive_error(error));
+            return;
         }
+    };
+
+    let mut response = remote::Response::new();
+    if let Some(command) = r.REMOTE.receive(byte, &r.STATE, &mut response) {
+        r.STATE.apply_command(command);
+        r.INTERRUPT_CONTROLLER.set_pending(stm32f103xx::Interrupt::EXTI1);
+    }
+    // The host sent more before reading the earlier responses
+    if !r.REMOTE_OUTPUT.push(&response) {
+        r.REMOTE.push_error(remote::Error::QueryInterrupted);
+    }
+}
+
+/// The SCPI error reported for a serial receive error
+fn receive_error(error: serial::Error) -> remote::Error {
+    match error {
+        serial::Error::Overrun => remote::Error::InputBufferOverrun,
+        serial::Error::Framing => remote::Error::FramingError,
+        serial::Error::Parity => remote::Error::ParityError,
+        _ => remote::Error::CommunicationError,
     }
 }
 
 fn state_changed(_t: &mut Threshold, mut r: EXTI1::Resources) {
-    let min_voltage: f32 = 1.291;
-    let max_voltage: f32 = 18.95 + min_voltage;
-    let voltage_multiplyer = 1.046;
-
-    let duty_percentage = voltage::pwm_percentage_for_voltage(
-        r.STATE.output_voltage(),
-        min_voltage,
-        max_voltage
+    // Write the current status
+    write_line(1, &mut r.LCD, &r.STATE.status_line().unwrap());
+
+    let current_percentage = current::pwm_percentage_for_current(
+        r.STATE.current_limit().amps(),
+        current::MAX_CURRENT
     );
+    let current_duty = (r.CURRENT_PWM.get_max_duty() as f32) * current_percentage;
+    r.CURRENT_PWM.set_duty(current_duty as u16);
+}
 
-    // Write the current status
-    write_line(1, &mut r.LCD, &r.STATE.get_display().unwrap());
 
-    let duty = (r.PWM.get_max_duty() as f32) * duty_percentage * voltage_multiplyer;
+/**
+  Measures the output voltage and trims the PWM duty until it matches the
+  setpoint. The open loop duty from the calibration is used as feedforward so
+  the controller only has to correct the calibration error.
+
+  While calibrating, the requested duty is output as is.
+
+  The measurements are checked by the protection first, so a trip turns the
+  output off in the same period.
+*/
+fn control_loop(_t: &mut Threshold, mut r: TIM4::Resources) {
+    // Clear the update flag
+    r.CONTROL_TIMER.wait().ok();
+    *r.UPTIME = r.UPTIME.wrapping_add(1_000 / CONTROL_FREQUENCY);
+
+    // Filtering removes switching spikes and ADC noise. The over-current
+    // protection waits for its delay anyway, so it can use the filtered current
+    r.ADC.select(CURRENT_CHANNEL);
+    let raw_current = r.CURRENT_FILTER.update(r.ADC.sample());
+    let current = adc_conversion::raw_to_voltage(raw_current, current::MEASUREMENT_FULL_SCALE);
+    r.STATE.set_measured_current(current);
+
+    // The filter delays the voltage, which the PI controller was not tuned for
+    // and which would let an over-voltage through for a few periods. Those get
+    // the raw sample and only the readout is filtered
+    r.ADC.select(VOLTAGE_CHANNEL);
+    let raw_voltage = r.ADC.sample();
+    let measured = adc_conversion::raw_to_voltage(raw_voltage, voltage::MEASUREMENT_FULL_SCALE);
+    let filtered = adc_conversion::raw_to_voltage(
+        r.VOLTAGE_FILTER.update(raw_voltage),
+        voltage::MEASUREMENT_FULL_SCALE
+    );
+    r.STATE.set_measured_voltage(measured);
+    r.STATE.set_filtered_voltage(filtered);
+
+    let tripped = r.STATE.update_protection(1_000 / CONTROL_FREQUENCY).is_some();
+    let mode_changed = r.STATE.update_mode().is_some();
+    let refresh = r.STATE.update_readout(1_000 / CONTROL_FREQUENCY);
+    if tripped || mode_changed || refresh {
+        // Show the fault, the new mode or the new measurements
+        r.INTERRUPT_CONTROLLER.set_pending(stm32f103xx::Interrupt::EXTI1);
+    }
+    let target = r.STATE.update_ramp(1_000 / CONTROL_FREQUENCY);
+
+    let duty_fraction = if !r.STATE.output_enabled() {
+        r.VOLTAGE_CONTROLLER.reset();
+        0.
+    }
+    else if let Some(duty) = r.STATE.calibration_duty() {
+        r.VOLTAGE_CONTROLLER.reset();
+        duty
+    }
+    else {
+        let feedforward = r.STATE.calibration().duty_for_voltage(target);
+        r.VOLTAGE_CONTROLLER.update(target, measured, feedforward)
+    };
+
+    let duty = (r.PWM.get_max_duty() as f32) * duty_fraction;
     r.PWM.set_duty(duty as u16);
 }
 
-
 fn output_switch_changed(_t: &mut Threshold, mut r: EXTI9_5::Resources) {
     r.STATE.set_output_switch_state(r.OUTPUT_SENSOR.is_low());
 
@@ -283,6 +573,7 @@ fn write_line(line: u8, lcd: &mut Lcd, message: &str) {
         lcd.set_cursor_pos(40);
     };
     const LINE_LENGTH: usize = 16;
+    let message = &message[..message.len().min(LINE_LENGTH)];
     let amount_of_padding = LINE_LENGTH - message.len();
 
     lcd.write_str(message);
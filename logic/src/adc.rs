@@ -0,0 +1,43 @@
+/// The largest value produced by the 12 bit ADC
+pub const ADC_MAX: u16 = 4095;
+
+/**
+  A source of raw ADC samples. Implemented by the ADC peripheral on the
+  controller and by fakes in tests
+*/
+pub trait Sampler {
+    fn sample(&mut self) -> u16;
+}
+
+/**
+  Converts a raw sample to the voltage on the other side of a divider which
+  produces `ADC_MAX` at `full_scale` volts
+*/
+pub fn raw_to_voltage(raw: u16, full_scale: f32) -> f32 {
+    (raw as f32) / (ADC_MAX as f32) * full_scale
+}
+
+/// Takes a sample from `sampler` and converts it to a voltage
+pub fn read_voltage<S: Sampler>(sampler: &mut S, full_scale: f32) -> f32 {
+    raw_to_voltage(sampler.sample(), full_scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Constant(u16);
+
+    impl Sampler for Constant {
+        fn sample(&mut self) -> u16 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn conversion() {
+        assert_eq!(read_voltage(&mut Constant(0), 20.), 0.);
+        assert_eq!(read_voltage(&mut Constant(ADC_MAX), 20.), 20.);
+        assert!((read_voltage(&mut Constant(2048), 20.) - 10.002442).abs() < 1e-4);
+    }
+}
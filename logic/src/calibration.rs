@@ -0,0 +1,274 @@
+use arrayvec::ArrayVec;
+
+/// The most points a calibration table can hold
+pub const MAX_POINTS: usize = 8;
+
+/// A PWM duty, as a fraction of the max duty, and the output voltage it produced
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CalibrationPoint {
+    pub duty: f32,
+    pub voltage: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FitError {
+    /// At least two points are needed to fit a line
+    TooFewPoints,
+    TooManyPoints,
+    /// A higher duty must always give a higher voltage
+    NotMonotonic,
+}
+
+/**
+  Piecewise linear mapping from target voltage to PWM duty.
+
+  The points are sorted by voltage. Targets outside the table are
+  extrapolated from the closest segment.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Calibration {
+    points: ArrayVec<[CalibrationPoint; MAX_POINTS]>,
+}
+
+impl Calibration {
+    /**
+      Builds a calibration table from measured points in any order.
+    */
+    pub fn fit(points: &[CalibrationPoint]) -> Result<Self, FitError> {
+        if points.len() < 2 {
+            return Err(FitError::TooFewPoints);
+        }
+        if points.len() > MAX_POINTS {
+            return Err(FitError::TooManyPoints);
+        }
+
+        let mut sorted = ArrayVec::<[CalibrationPoint; MAX_POINTS]>::new();
+        for point in points {
+            if !point.duty.is_finite() || !point.voltage.is_finite() {
+                return Err(FitError::NotMonotonic);
+            }
+            sorted.push(*point);
+        }
+        sorted.sort_unstable_by(|a, b| a.duty.partial_cmp(&b.duty).unwrap());
+
+        for pair in sorted.windows(2) {
+            if pair[1].duty <= pair[0].duty || pair[1].voltage <= pair[0].voltage {
+                return Err(FitError::NotMonotonic);
+            }
+        }
+
+        Ok(Self { points: sorted })
+    }
+
+    pub fn points(&self) -> &[CalibrationPoint] {
+        &self.points
+    }
+
+    /// Returns the duty which produces `target` volts
+    pub fn duty_for_voltage(&self, target: f32) -> f32 {
+        let (low, high) = self.segment(|point| point.voltage >= target);
+        let fraction = (target - low.voltage) / (high.voltage - low.voltage);
+        low.duty + fraction * (high.duty - low.duty)
+    }
+
+    /// Returns the voltage produced by `duty`. The inverse of `duty_for_voltage`
+    pub fn voltage_for_duty(&self, duty: f32) -> f32 {
+        let (low, high) = self.segment(|point| point.duty >= duty);
+        let fraction = (duty - low.duty) / (high.duty - low.duty);
+        low.voltage + fraction * (high.voltage - low.voltage)
+    }
+
+    /**
+      Returns the first segment whose upper end satisfies `is_above`, or the
+      last segment if none does
+    */
+    fn segment<F>(&self, is_above: F) -> (CalibrationPoint, CalibrationPoint)
+        where F: Fn(&CalibrationPoint) -> bool
+    {
+        let last = self.points.len() - 1;
+        let end = self.points[1..].iter()
+            .position(is_above)
+            .map(|i| i + 1)
+            .unwrap_or(last);
+        (self.points[end - 1], self.points[end])
+    }
+}
+
+impl Default for Calibration {
+    /**
+      The calibration measured on the first board: 0% duty gives 1.291 V and
+      the duty needed for each volt above that is 1.046 times what an 18.95 V
+      span would suggest
+    */
+    fn default() -> Self {
+        let min_voltage = 1.291;
+        let span = 18.95;
+        let voltage_multiplyer = 1.046;
+        Self::fit(&[
+            CalibrationPoint { duty: 0., voltage: min_voltage },
+            CalibrationPoint { duty: 1., voltage: min_voltage + span / voltage_multiplyer },
+        ]).unwrap()
+    }
+}
+
+
+/**
+  Points recorded so far by the keypad calibration procedure. The supply
+  outputs `duty` open loop while the user measures the output.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct CalibrationSession {
+    duty: f32,
+    points: ArrayVec<[CalibrationPoint; MAX_POINTS]>,
+}
+
+impl CalibrationSession {
+    pub fn new(duty: f32) -> Self {
+        Self {
+            duty,
+            points: ArrayVec::new(),
+        }
+    }
+
+    pub fn duty(&self) -> f32 {
+        self.duty
+    }
+
+    pub fn set_duty(&mut self, duty: f32) {
+        self.duty = duty;
+    }
+
+    /**
+      Records the voltage measured at the current duty. Returns false if the
+      session is full.
+    */
+    pub fn record(&mut self, voltage: f32) -> bool {
+        self.points.try_push(CalibrationPoint { duty: self.duty, voltage }).is_ok()
+    }
+
+    pub fn finish(&self) -> Result<Calibration, FitError> {
+        Calibration::fit(&self.points)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use units::MilliVolts;
+    use voltage::{pwm_percentage_for_voltage, MAX_VOLTAGE, MIN_VOLTAGE};
+
+    fn point(duty: f32, voltage: f32) -> CalibrationPoint {
+        CalibrationPoint { duty, voltage }
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn default_matches_old_constants() {
+        let calibration = Calibration::default();
+        for &target in &[1291, 3300, 5000, 12000, 20000] {
+            let target = MilliVolts(target);
+            let old = pwm_percentage_for_voltage(target, MIN_VOLTAGE, MAX_VOLTAGE).unwrap() * 1.046;
+            assert_close(calibration.duty_for_voltage(target.volts()), old);
+        }
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        let calibration = Calibration::fit(&[
+            point(0., 1.),
+            point(0.5, 9.),
+            point(1., 21.),
+        ]).unwrap();
+
+        assert_close(calibration.duty_for_voltage(1.), 0.);
+        assert_close(calibration.duty_for_voltage(5.), 0.25);
+        assert_close(calibration.duty_for_voltage(9.), 0.5);
+        assert_close(calibration.duty_for_voltage(15.), 0.75);
+        assert_close(calibration.duty_for_voltage(21.), 1.);
+    }
+
+    #[test]
+    fn extrapolates_outside_table() {
+        let calibration = Calibration::fit(&[
+            point(0.2, 5.),
+            point(0.4, 9.),
+            point(0.6, 15.),
+        ]).unwrap();
+
+        assert_close(calibration.duty_for_voltage(3.), 0.1);
+        assert_close(calibration.duty_for_voltage(18.), 0.7);
+    }
+
+    #[test]
+    fn voltage_for_duty_is_inverse() {
+        let calibration = Calibration::fit(&[
+            point(0.1, 2.),
+            point(0.5, 9.),
+            point(0.9, 19.),
+        ]).unwrap();
+        for &target in &[1., 2., 4.5, 9., 12., 19., 20.] {
+            let duty = calibration.duty_for_voltage(target);
+            assert_close(calibration.voltage_for_duty(duty), target);
+        }
+    }
+
+    #[test]
+    fn fit_sorts_points() {
+        let calibration = Calibration::fit(&[
+            point(0.9, 19.),
+            point(0.1, 2.),
+            point(0.5, 9.),
+        ]).unwrap();
+        assert_eq!(
+            calibration.points(),
+            &[point(0.1, 2.), point(0.5, 9.), point(0.9, 19.)]
+        );
+    }
+
+    #[test]
+    fn fit_errors() {
+        assert_eq!(Calibration::fit(&[]), Err(FitError::TooFewPoints));
+        assert_eq!(Calibration::fit(&[point(0.5, 5.)]), Err(FitError::TooFewPoints));
+        assert_eq!(
+            Calibration::fit(&[point(0.5, 5.), point(0.5, 6.)]),
+            Err(FitError::NotMonotonic)
+        );
+        assert_eq!(
+            Calibration::fit(&[point(0.2, 5.), point(0.5, 4.)]),
+            Err(FitError::NotMonotonic)
+        );
+        assert_eq!(
+            Calibration::fit(&[point(0.2, 5.), point(0.5, f32::NAN)]),
+            Err(FitError::NotMonotonic)
+        );
+        assert_eq!(
+            Calibration::fit(&[point(0., 1.); MAX_POINTS + 1]),
+            Err(FitError::TooManyPoints)
+        );
+    }
+
+    #[test]
+    fn session_records_points_at_current_duty() {
+        let mut session = CalibrationSession::new(0.25);
+        assert!(session.record(5.));
+        session.set_duty(0.75);
+        assert!(session.record(15.));
+
+        let calibration = session.finish().unwrap();
+        assert_eq!(calibration.points(), &[point(0.25, 5.), point(0.75, 15.)]);
+    }
+
+    #[test]
+    fn session_is_bounded() {
+        let mut session = CalibrationSession::new(0.);
+        for i in 0..MAX_POINTS {
+            session.set_duty(i as f32 / 10.);
+            assert!(session.record(i as f32));
+        }
+        assert!(!session.record(100.));
+    }
+}
@@ -0,0 +1,190 @@
+/// Proportional gain of the output voltage loop, in duty per volt
+pub const VOLTAGE_KP: f32 = 0.03;
+/// Integral gain of the output voltage loop, in duty per volt second
+pub const VOLTAGE_KI: f32 = 1.;
+
+/**
+  PI controller which trims a feedforward duty until the measured value
+  matches the target.
+
+  The output is clamped to `[min_output, max_output]`. While the output is
+  saturated the integrator only accepts changes which move it out of
+  saturation, which keeps it from winding up when the target can't be reached.
+*/
+pub struct PiController {
+    kp: f32,
+    ki: f32,
+    period: f32,
+    min_output: f32,
+    max_output: f32,
+    integral: f32,
+}
+
+impl PiController {
+    /**
+      `period` is the time in seconds between calls to `update`
+    */
+    pub fn new(kp: f32, ki: f32, period: f32, min_output: f32, max_output: f32) -> Self {
+        Self {
+            kp,
+            ki,
+            period,
+            min_output,
+            max_output,
+            integral: 0.,
+        }
+    }
+
+    /**
+      Runs one step of the controller and returns the new output.
+    */
+    pub fn update(&mut self, target: f32, measured: f32, feedforward: f32) -> f32 {
+        let error = target - measured;
+        let integral = self.integral + error * self.ki * self.period;
+        let unclamped = feedforward + self.kp * error + integral;
+
+        let output = if unclamped > self.max_output {
+            self.max_output
+        }
+        else if unclamped < self.min_output {
+            self.min_output
+        }
+        else {
+            unclamped
+        };
+
+        let saturated_high = unclamped > self.max_output && error > 0.;
+        let saturated_low = unclamped < self.min_output && error < 0.;
+        if !saturated_high && !saturated_low {
+            self.integral = integral;
+        }
+
+        output
+    }
+
+    /// Forgets the accumulated error, for example when the output is turned off
+    pub fn reset(&mut self) {
+        self.integral = 0.;
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use calibration::Calibration;
+
+    const PERIOD: f32 = 0.001;
+
+    fn controller() -> PiController {
+        PiController::new(VOLTAGE_KP, VOLTAGE_KI, PERIOD, 0., 1.)
+    }
+
+    /**
+      First order model of the output stage. The gain is off from the one
+      in the default calibration to give the controller something to correct.
+    */
+    struct Plant {
+        voltage: f32,
+        gain_error: f32,
+        time_constant: f32,
+        calibration: Calibration,
+    }
+
+    impl Plant {
+        fn new(gain_error: f32) -> Self {
+            let calibration = Calibration::default();
+            Self {
+                voltage: calibration.voltage_for_duty(0.),
+                gain_error,
+                time_constant: 0.01,
+                calibration,
+            }
+        }
+
+        fn step(&mut self, duty: f32) {
+            let min_voltage = self.calibration.voltage_for_duty(0.);
+            let steady_state = min_voltage
+                + (self.calibration.voltage_for_duty(duty) - min_voltage) * self.gain_error;
+            self.voltage += (steady_state - self.voltage) * PERIOD / self.time_constant;
+        }
+    }
+
+    /// Runs the loop for `steps` periods and returns the highest voltage seen
+    fn run(controller: &mut PiController, plant: &mut Plant, target: f32, steps: usize) -> f32 {
+        let mut max = plant.voltage;
+        for _ in 0..steps {
+            let feedforward = plant.calibration.duty_for_voltage(target);
+            let duty = controller.update(target, plant.voltage, feedforward);
+            plant.step(duty);
+            max = max.max(plant.voltage);
+        }
+        max
+    }
+
+    #[test]
+    fn open_loop_is_off_with_gain_error() {
+        let mut plant = Plant::new(0.9);
+        for _ in 0..1000 {
+            let duty = plant.calibration.duty_for_voltage(12.);
+            plant.step(duty);
+        }
+        assert!((plant.voltage - 12.).abs() > 0.5);
+    }
+
+    #[test]
+    fn converges_to_target() {
+        for &gain_error in &[0.8, 0.9, 1., 1.1] {
+            let mut controller = controller();
+            let mut plant = Plant::new(gain_error);
+            run(&mut controller, &mut plant, 12., 2000);
+            assert!(
+                (plant.voltage - 12.).abs() < 0.01,
+                "gain error {} settled at {}", gain_error, plant.voltage
+            );
+        }
+    }
+
+    #[test]
+    fn overshoot_is_small() {
+        let mut controller = controller();
+        let mut plant = Plant::new(0.9);
+        let max = run(&mut controller, &mut plant, 5., 2000);
+        assert!(max < 5. * 1.02, "overshoot to {}", max);
+    }
+
+    #[test]
+    fn no_windup_on_unreachable_target() {
+        let mut controller = controller();
+        let mut plant = Plant::new(0.9);
+        // Far above what the plant can produce
+        run(&mut controller, &mut plant, 30., 5000);
+        let integral = controller.integral();
+        assert!(integral < 0.5, "integral wound up to {}", integral);
+
+        let max = run(&mut controller, &mut plant, 5., 300);
+        assert!(max < 25.);
+        run(&mut controller, &mut plant, 5., 1000);
+        assert!((plant.voltage - 5.).abs() < 0.01, "settled at {}", plant.voltage);
+    }
+
+    #[test]
+    fn output_is_clamped() {
+        let mut controller = controller();
+        assert_eq!(controller.update(100., 0., 1.), 1.);
+        assert_eq!(controller.update(0., 100., 0.), 0.);
+    }
+
+    #[test]
+    fn reset_clears_integral() {
+        let mut controller = controller();
+        controller.update(10., 5., 0.5);
+        assert!(controller.integral() != 0.);
+        controller.reset();
+        assert_eq!(controller.integral(), 0.);
+    }
+}
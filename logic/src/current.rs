@@ -0,0 +1,16 @@
+use units::MilliAmps;
+
+/// The current limit at 100% duty, also the highest limit that can be set
+pub const MAX_CURRENT_MA: MilliAmps = MilliAmps(3000);
+/// `MAX_CURRENT_MA` in A
+pub const MAX_CURRENT: f32 = MAX_CURRENT_MA.0 as f32 / 1000.;
+
+/**
+  The output current which makes the current sense produce a full scale ADC
+  reading. A 0.1 ohm shunt amplified 10 times gives 1 V per A
+*/
+pub const MEASUREMENT_FULL_SCALE: f32 = 3.3 / (0.1 * 10.);
+
+pub fn pwm_percentage_for_current(target: f32, max_current: f32) -> f32 {
+    target / max_current
+}
@@ -0,0 +1,427 @@
+/*!
+  Turns raw keypad scans into key events.
+
+  The keypad is scanned periodically and every scan is passed to
+  `Debouncer::update` along with a timestamp in milliseconds. A key has to
+  read the same for `Timing::debounce` milliseconds before its state changes.
+*/
+// See keypad.rs
+#![allow(deprecated)]
+
+use arrayvec::ArrayVec;
+
+use keypad::{Keypad, MAX_SCAN_KEYS};
+use hal::digital::{InputPin, OutputPin};
+
+use core::borrow::{Borrow, BorrowMut};
+
+/// The most keys that are tracked at the same time. Further keys are ignored
+pub const MAX_KEYS: usize = 4;
+/// The most events a single update can produce
+pub const MAX_EVENTS: usize = MAX_KEYS * 3;
+
+pub type Events = ArrayVec<[KeyEvent; MAX_EVENTS]>;
+
+/// Timing of key events in milliseconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timing {
+    /// How long a key has to read the same before the change is accepted
+    pub debounce: u32,
+    /// How long a key has to be held before `LongPress` is emitted
+    pub long_press: u32,
+    /// How long a key has to be held before the first `Repeat`, `None` disables repeating
+    pub repeat_delay: Option<u32>,
+    /// Time between `Repeat` events after the first one
+    pub repeat_interval: u32,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            debounce: 20,
+            long_press: 500,
+            repeat_delay: Some(600),
+            repeat_interval: 100,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    Press,
+    /// The key was released after being held for `held` milliseconds
+    Release { held: u32 },
+    /// Emitted periodically while a key is held
+    Repeat,
+    /// Emitted once when a key has been held for `Timing::long_press`
+    LongPress,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyEvent {
+    /// The keypad coordinate as reported by `Keypad::read_all_coords`
+    pub key: (u8, u8),
+    pub kind: EventKind,
+    /// When the event happened in milliseconds
+    pub time: u32,
+}
+
+/// Two keys that trigger `action` when held down together
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Chord<T> {
+    pub keys: [(u8, u8); 2],
+    pub action: T,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct KeyState {
+    key: (u8, u8),
+    /// The state read by the last scan
+    raw: bool,
+    /// When `raw` last changed
+    changed_at: u32,
+    /// The debounced state
+    pressed: bool,
+    pressed_at: u32,
+    long_press_sent: bool,
+    next_repeat: Option<u32>,
+}
+
+pub struct Debouncer {
+    timing: Timing,
+    keys: ArrayVec<[KeyState; MAX_KEYS]>,
+}
+
+impl Debouncer {
+    pub fn new(timing: Timing) -> Self {
+        Self {
+            timing,
+            keys: ArrayVec::new(),
+        }
+    }
+
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    /// Returns true if any key is pressed or bouncing
+    pub fn is_active(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Returns true if `key` is down after debouncing
+    pub fn is_pressed(&self, key: (u8, u8)) -> bool {
+        self.keys.iter().any(|state| state.key == key && state.pressed)
+    }
+
+    /**
+      Scans `keypad` and returns the events caused by the scan. Keys that may
+      be phantom keys keep the state they had before the ambiguous scan.
+    */
+    pub fn scan<R, C, I, O>(&mut self, keypad: &mut Keypad<R, C, I, O>, now: u32) -> Events
+    where R: Borrow<[I]>,
+          C: BorrowMut<[O]>,
+          I: InputPin,
+          O: OutputPin,
+    {
+        let scan = keypad.scan();
+        let mut down = ArrayVec::<[(u8, u8); MAX_SCAN_KEYS]>::new();
+        for &key in scan.keys() {
+            let was_down = self.keys.iter().any(|state| state.key == key && state.raw);
+            if !scan.is_ghosted(key) || was_down {
+                down.push(key);
+            }
+        }
+        self.update(&down, now)
+    }
+
+    /**
+      Returns the action of the first chord in `chords` that was completed by
+      a key press in `events`.
+    */
+    pub fn chord<T: Copy>(&self, events: &[KeyEvent], chords: &[Chord<T>]) -> Option<T> {
+        events.iter()
+            .filter(|event| event.kind == EventKind::Press)
+            .filter_map(|event| {
+                chords.iter().find(|chord| {
+                    let [first, second] = chord.keys;
+                    (event.key == first && self.is_pressed(second))
+                        || (event.key == second && self.is_pressed(first))
+                })
+            })
+            .map(|chord| chord.action)
+            .next()
+    }
+
+    /**
+      Updates the key states from the keys that are currently down and
+      returns the resulting events. `now` may wrap around.
+    */
+    pub fn update(&mut self, down: &[(u8, u8)], now: u32) -> Events {
+        for &key in down {
+            if !self.keys.iter().any(|state| state.key == key) {
+                // The state is full if too many keys are down, the extra keys are ignored
+                let _ = self.keys.try_push(KeyState {
+                    key,
+                    raw: false,
+                    changed_at: now,
+                    pressed: false,
+                    pressed_at: now,
+                    long_press_sent: false,
+                    next_repeat: None,
+                });
+            }
+        }
+
+        let timing = self.timing;
+        let mut events = Events::new();
+        for state in self.keys.iter_mut() {
+            let raw = down.contains(&state.key);
+            if raw != state.raw {
+                state.raw = raw;
+                state.changed_at = now;
+            }
+
+            let key = state.key;
+            let mut push = |kind| {
+                events.push(KeyEvent { key, kind, time: now });
+            };
+
+            if state.raw != state.pressed && now.wrapping_sub(state.changed_at) >= timing.debounce {
+                state.pressed = state.raw;
+                if state.pressed {
+                    state.pressed_at = now;
+                    state.long_press_sent = false;
+                    state.next_repeat = timing.repeat_delay.map(|delay| now.wrapping_add(delay));
+                    push(EventKind::Press);
+                }
+                else {
+                    push(EventKind::Release { held: now.wrapping_sub(state.pressed_at) });
+                }
+            }
+
+            if state.pressed {
+                if !state.long_press_sent
+                    && now.wrapping_sub(state.pressed_at) >= timing.long_press
+                {
+                    state.long_press_sent = true;
+                    push(EventKind::LongPress);
+                }
+                if let Some(next_repeat) = state.next_repeat {
+                    // Signed difference so that the comparison survives wrapping
+                    if now.wrapping_sub(next_repeat) as i32 >= 0 {
+                        state.next_repeat = Some(next_repeat.wrapping_add(timing.repeat_interval));
+                        push(EventKind::Repeat);
+                    }
+                }
+            }
+        }
+
+        // Forget keys that are up and were either released or only bounced
+        self.keys.retain(|state| state.raw || state.pressed);
+
+        events
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use keypad::mock::{Matrix, keypad};
+
+    const NO_REPEAT: Timing = Timing {
+        debounce: 5,
+        long_press: 50,
+        repeat_delay: None,
+        repeat_interval: 0,
+    };
+
+    /**
+      Scans a keypad every millisecond from 0 until `end`. Each step of the
+      trace sets the pressed keys from the given time onwards.
+    */
+    fn run_trace(
+        timing: Timing,
+        trace: &[(u32, &[(usize, usize)])],
+        end: u32
+    ) -> ArrayVec<[KeyEvent; 16]> {
+        let matrix = Matrix::new(&[]);
+        let mut keypad = keypad(&matrix);
+        let mut debouncer = Debouncer::new(timing);
+        let mut events = ArrayVec::new();
+        for now in 0..end {
+            if let Some(&(_, keys)) = trace.iter().rev().find(|&&(time, _)| time <= now) {
+                matrix.set_pressed(keys);
+            }
+            events.extend(debouncer.scan(&mut keypad, now));
+        }
+        events
+    }
+
+    fn event(key: (u8, u8), kind: EventKind, time: u32) -> KeyEvent {
+        KeyEvent { key, kind, time }
+    }
+
+    #[test]
+    fn clean_press_and_release() {
+        let events = run_trace(NO_REPEAT, &[(10, &[(1, 2)]), (30, &[])], 50);
+        assert_eq!(events.as_slice(), &[
+            event((1, 2), EventKind::Press, 15),
+            event((1, 2), EventKind::Release { held: 20 }, 35),
+        ]);
+    }
+
+    #[test]
+    fn bounces_are_filtered() {
+        let trace: &[(u32, &[(usize, usize)])] = &[
+            (10, &[(0, 0)]),
+            (11, &[]),
+            (13, &[(0, 0)]),
+            (14, &[]),
+            (15, &[(0, 0)]),
+            (30, &[]),
+            (32, &[(0, 0)]),
+            (33, &[]),
+        ];
+        let events = run_trace(NO_REPEAT, trace, 60);
+        assert_eq!(events.as_slice(), &[
+            event((0, 0), EventKind::Press, 20),
+            event((0, 0), EventKind::Release { held: 18 }, 38),
+        ]);
+    }
+
+    #[test]
+    fn short_glitch_produces_no_events() {
+        let events = run_trace(NO_REPEAT, &[(10, &[(2, 3)]), (13, &[])], 40);
+        assert_eq!(events.as_slice(), &[]);
+    }
+
+    #[test]
+    fn long_press() {
+        let events = run_trace(NO_REPEAT, &[(0, &[(1, 3)]), (100, &[])], 120);
+        assert_eq!(events.as_slice(), &[
+            event((1, 3), EventKind::Press, 5),
+            event((1, 3), EventKind::LongPress, 55),
+            event((1, 3), EventKind::Release { held: 100 }, 105),
+        ]);
+    }
+
+    #[test]
+    fn repeats_while_held() {
+        let timing = Timing {
+            repeat_delay: Some(20),
+            repeat_interval: 10,
+            long_press: 1000,
+            ..NO_REPEAT
+        };
+        let events = run_trace(timing, &[(0, &[(0, 1)]), (50, &[])], 60);
+        assert_eq!(events.as_slice(), &[
+            event((0, 1), EventKind::Press, 5),
+            event((0, 1), EventKind::Repeat, 25),
+            event((0, 1), EventKind::Repeat, 35),
+            event((0, 1), EventKind::Repeat, 45),
+            event((0, 1), EventKind::Release { held: 50 }, 55),
+        ]);
+    }
+
+    #[test]
+    fn overlapping_keys_are_independent() {
+        let trace: &[(u32, &[(usize, usize)])] = &[
+            (0, &[(0, 0)]),
+            (10, &[(0, 0), (2, 1)]),
+            (20, &[(2, 1)]),
+            (30, &[]),
+        ];
+        let events = run_trace(NO_REPEAT, trace, 40);
+        assert_eq!(events.as_slice(), &[
+            event((0, 0), EventKind::Press, 5),
+            event((2, 1), EventKind::Press, 15),
+            event((0, 0), EventKind::Release { held: 20 }, 25),
+            event((2, 1), EventKind::Release { held: 20 }, 35),
+        ]);
+    }
+
+    #[test]
+    fn phantom_keys_are_not_pressed() {
+        let trace: &[(u32, &[(usize, usize)])] = &[
+            (0, &[(0, 0), (0, 2)]),
+            (10, &[(0, 0), (0, 2), (1, 0)]),
+            (30, &[(0, 0), (0, 2)]),
+            (40, &[]),
+        ];
+        let events = run_trace(NO_REPEAT, trace, 50);
+        // (1, 0) and the phantom (1, 2) are ambiguous while all three keys are down
+        assert_eq!(events.as_slice(), &[
+            event((0, 0), EventKind::Press, 5),
+            event((0, 2), EventKind::Press, 5),
+            event((0, 0), EventKind::Release { held: 40 }, 45),
+            event((0, 2), EventKind::Release { held: 40 }, 45),
+        ]);
+    }
+
+    #[test]
+    fn chords() {
+        let chords = [
+            Chord { keys: [(0, 0), (2, 0)], action: 'x' },
+            Chord { keys: [(1, 1), (1, 3)], action: 'y' },
+        ];
+        let matrix = Matrix::new(&[]);
+        let mut keypad = keypad(&matrix);
+        let mut debouncer = Debouncer::new(NO_REPEAT);
+        let mut actions = ArrayVec::<[char; 4]>::new();
+        let trace: &[(u32, &[(usize, usize)])] = &[
+            (0, &[(2, 0)]),
+            (20, &[(2, 0), (0, 0)]),
+            (40, &[]),
+            (50, &[(1, 3)]),
+            (60, &[(1, 3), (0, 0)]),
+            (70, &[(1, 3), (0, 0), (1, 1)]),
+        ];
+        for now in 0..80 {
+            if let Some(&(_, keys)) = trace.iter().rev().find(|&&(time, _)| time <= now) {
+                matrix.set_pressed(keys);
+            }
+            let events = debouncer.scan(&mut keypad, now);
+            if let Some(action) = debouncer.chord(&events, &chords) {
+                actions.push(action);
+            }
+        }
+        assert_eq!(actions.as_slice(), &['x', 'y']);
+    }
+
+    #[test]
+    fn extra_keys_are_ignored() {
+        let mut debouncer = Debouncer::new(NO_REPEAT);
+        let keys = [(0, 0), (0, 1), (0, 2), (0, 3), (1, 0)];
+        debouncer.update(&keys, 0);
+        let events = debouncer.update(&keys, 10);
+        assert_eq!(events.len(), MAX_KEYS);
+        assert!(events.iter().all(|event| event.key != (1, 0)));
+    }
+
+    #[test]
+    fn timestamps_wrap_around() {
+        let mut debouncer = Debouncer::new(NO_REPEAT);
+        let start = u32::MAX - 2;
+        assert_eq!(debouncer.update(&[(1, 1)], start).as_slice(), &[]);
+        assert_eq!(
+            debouncer.update(&[(1, 1)], start.wrapping_add(5)).as_slice(),
+            &[event((1, 1), EventKind::Press, 2)]
+        );
+        assert_eq!(
+            debouncer.update(&[(1, 1)], start.wrapping_add(55)).as_slice(),
+            &[event((1, 1), EventKind::LongPress, 52)]
+        );
+    }
+
+    #[test]
+    fn idle_after_release() {
+        let mut debouncer = Debouncer::new(NO_REPEAT);
+        debouncer.update(&[(0, 0)], 0);
+        assert!(debouncer.is_active());
+        debouncer.update(&[], 1);
+        assert!(!debouncer.is_active());
+    }
+}
@@ -0,0 +1,253 @@
+use arrayvec::ArrayString;
+use itoa;
+
+/// Most digits that can be typed after the decimal point
+const MAX_FRACTION_DIGITS: u8 = 3;
+
+/// The unit a number is typed in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntryUnit {
+    /// Volts or amps, with up to three fractional digits
+    Whole,
+    /// Millivolts or milliamps
+    Milli,
+}
+
+/**
+  A number being typed on the keypad.
+
+  Pressing the decimal point key on an empty entry switches between whole and
+  milli units, otherwise it starts the fractional part of a number in whole
+  units.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Entry {
+    digits: u32,
+    length: u8,
+    fraction_digits: Option<u8>,
+    unit: EntryUnit,
+}
+
+impl Entry {
+    pub fn new(unit: EntryUnit) -> Self {
+        Self {
+            digits: 0,
+            length: 0,
+            fraction_digits: None,
+            unit,
+        }
+    }
+
+    pub fn unit(&self) -> EntryUnit {
+        self.unit
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0 && self.fraction_digits.is_none()
+    }
+
+    /**
+      Appends a digit. Digits beyond the resolution of the entry are ignored and
+      values too large to represent saturate.
+    */
+    pub fn push_digit(self, digit: u8) -> Self {
+        let fraction_digits = match self.fraction_digits {
+            Some(MAX_FRACTION_DIGITS) => return self,
+            Some(n) => Some(n + 1),
+            None => None,
+        };
+        Self {
+            digits: self.digits.saturating_mul(10).saturating_add(digit as u32),
+            length: self.length.saturating_add(1),
+            fraction_digits,
+            ..self
+        }
+    }
+
+    pub fn push_decimal_point(self) -> Self {
+        if self.is_empty() {
+            let unit = match self.unit {
+                EntryUnit::Whole => EntryUnit::Milli,
+                EntryUnit::Milli => EntryUnit::Whole,
+            };
+            Self { unit, ..self }
+        }
+        else if self.unit == EntryUnit::Whole && self.fraction_digits.is_none() {
+            Self { fraction_digits: Some(0), ..self }
+        }
+        else {
+            self
+        }
+    }
+
+    /// Removes the last typed digit or decimal point
+    pub fn pop(self) -> Self {
+        match self.fraction_digits {
+            Some(0) => Self { fraction_digits: None, ..self },
+            Some(n) => Self {
+                digits: self.digits / 10,
+                length: self.length - 1,
+                fraction_digits: Some(n - 1),
+                ..self
+            },
+            None if self.length > 0 => Self {
+                digits: self.digits / 10,
+                length: self.length - 1,
+                ..self
+            },
+            None => self,
+        }
+    }
+
+    /// Removes everything typed so far but keeps the unit
+    pub fn clear(self) -> Self {
+        Self::new(self.unit)
+    }
+
+    /// The entered value in thousandths of the whole unit
+    pub fn thousandths(&self) -> u32 {
+        match self.unit {
+            EntryUnit::Milli => self.digits,
+            EntryUnit::Whole => {
+                let fraction_digits = self.fraction_digits.unwrap_or(0);
+                let scale = 10u32.pow((MAX_FRACTION_DIGITS - fraction_digits) as u32);
+                self.digits.saturating_mul(scale)
+            }
+        }
+    }
+
+    /**
+      Writes the number as typed followed by the unit, for example "12.5 V" or
+      "500 mV". `base_unit` is the symbol of the whole unit.
+    */
+    pub fn push_display(&self, result: &mut ArrayString<[u8; 32]>, base_unit: &str) {
+        let mut buffer = itoa::Buffer::new();
+        match self.fraction_digits {
+            Some(fraction_digits) => {
+                let scale = 10u32.pow(fraction_digits as u32);
+                result.push_str(buffer.format(self.digits / scale));
+                result.push('.');
+                if fraction_digits > 0 {
+                    let fraction = self.digits % scale;
+                    for _ in 0..(fraction_digits as usize - decimal_length(fraction)) {
+                        result.push('0');
+                    }
+                    result.push_str(buffer.format(fraction));
+                }
+            }
+            None => result.push_str(buffer.format(self.digits)),
+        }
+        result.push(' ');
+        if self.unit == EntryUnit::Milli {
+            result.push('m');
+        }
+        result.push_str(base_unit);
+    }
+}
+
+fn decimal_length(mut value: u32) -> usize {
+    let mut length = 1;
+    while value >= 10 {
+        value /= 10;
+        length += 1;
+    }
+    length
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_entry(unit: EntryUnit, input: &str) -> Entry {
+        input.chars().fold(Entry::new(unit), |entry, c| {
+            if c == '.' {
+                entry.push_decimal_point()
+            }
+            else {
+                entry.push_digit(c.to_digit(10).unwrap() as u8)
+            }
+        })
+    }
+
+    fn display(entry: &Entry) -> ArrayString<[u8; 32]> {
+        let mut result = ArrayString::new();
+        entry.push_display(&mut result, "V");
+        result
+    }
+
+    #[test]
+    fn whole_units() {
+        assert_eq!(type_entry(EntryUnit::Whole, "12").thousandths(), 12000);
+        assert_eq!(type_entry(EntryUnit::Whole, "12.").thousandths(), 12000);
+        assert_eq!(type_entry(EntryUnit::Whole, "12.5").thousandths(), 12500);
+        assert_eq!(type_entry(EntryUnit::Whole, "12.345").thousandths(), 12345);
+        assert_eq!(type_entry(EntryUnit::Whole, "0.05").thousandths(), 50);
+    }
+
+    #[test]
+    fn extra_fraction_digits_are_ignored() {
+        assert_eq!(type_entry(EntryUnit::Whole, "1.23456").thousandths(), 1234);
+    }
+
+    #[test]
+    fn second_decimal_point_is_ignored() {
+        assert_eq!(type_entry(EntryUnit::Whole, "1.2.3").thousandths(), 1230);
+    }
+
+    #[test]
+    fn milli_units() {
+        assert_eq!(type_entry(EntryUnit::Milli, "12500").thousandths(), 12500);
+        assert_eq!(type_entry(EntryUnit::Milli, "12.5").thousandths(), 125);
+    }
+
+    #[test]
+    fn decimal_point_on_empty_entry_switches_unit() {
+        assert_eq!(type_entry(EntryUnit::Whole, ".").unit(), EntryUnit::Milli);
+        assert_eq!(type_entry(EntryUnit::Whole, "..").unit(), EntryUnit::Whole);
+        assert_eq!(type_entry(EntryUnit::Whole, ".500").thousandths(), 500);
+        // A leading zero is needed for values below one
+        assert_eq!(type_entry(EntryUnit::Whole, "0.5").thousandths(), 500);
+    }
+
+    #[test]
+    fn pop_reverses_typing() {
+        let typed = "10.05";
+        for i in 0..typed.len() {
+            let mut entry = type_entry(EntryUnit::Whole, typed);
+            for _ in 0..(typed.len() - i) {
+                entry = entry.pop();
+            }
+            assert_eq!(entry, type_entry(EntryUnit::Whole, &typed[..i]));
+        }
+    }
+
+    #[test]
+    fn pop_on_empty_keeps_unit() {
+        let entry = type_entry(EntryUnit::Whole, ".");
+        assert_eq!(entry.pop(), entry);
+    }
+
+    #[test]
+    fn clear_keeps_unit() {
+        let entry = type_entry(EntryUnit::Whole, ".123").clear();
+        assert_eq!(entry, Entry::new(EntryUnit::Milli));
+    }
+
+    #[test]
+    fn saturates() {
+        assert_eq!(type_entry(EntryUnit::Milli, "99999999999").thousandths(), u32::MAX);
+        assert_eq!(type_entry(EntryUnit::Whole, "9999999").thousandths(), u32::MAX);
+    }
+
+    #[test]
+    fn displays_partial_values() {
+        assert_eq!(display(&Entry::new(EntryUnit::Whole)).as_str(), "0 V");
+        assert_eq!(display(&type_entry(EntryUnit::Whole, "12")).as_str(), "12 V");
+        assert_eq!(display(&type_entry(EntryUnit::Whole, "12.")).as_str(), "12. V");
+        assert_eq!(display(&type_entry(EntryUnit::Whole, "12.50")).as_str(), "12.50 V");
+        assert_eq!(display(&type_entry(EntryUnit::Whole, "0.005")).as_str(), "0.005 V");
+        assert_eq!(display(&type_entry(EntryUnit::Whole, ".")).as_str(), "0 mV");
+        assert_eq!(display(&type_entry(EntryUnit::Whole, ".500")).as_str(), "500 mV");
+    }
+}
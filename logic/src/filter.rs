@@ -0,0 +1,410 @@
+/*!
+  Fixed point filters for raw ADC samples.
+
+  The ADC output is noisy, and switching noise from the PWM stages causes the
+  odd sample far off the real value. The filters work on the raw 12 bit
+  samples so they don't need floating point.
+
+  - `MovingAverage` is the mean of the last N samples.
+  - `Exponential` behaves like an RC low-pass, see `math/filter.py`.
+  - `Median` is the median of the last N samples, which rejects spikes
+    shorter than half the window.
+
+  `Measurement` chains a short median with an exponential filter and is what
+  the measurement paths use for the readout, the regulation mode and the
+  over-current protection. It delays the signal, so the voltage control loop
+  and the over-voltage protection use the raw samples instead.
+*/
+
+use arrayvec::{Array, ArrayVec};
+
+/// Fraction bits of the fixed point values in `Exponential`
+const FRACTION_BITS: u32 = 16;
+const ONE: u32 = 1 << FRACTION_BITS;
+
+/// Time constant of the measurement filters in µs
+pub const MEASUREMENT_TIME_CONSTANT: u32 = 2_000;
+
+pub trait Filter {
+    /// Adds a sample and returns the filtered value
+    fn update(&mut self, sample: u16) -> u16;
+    /// Forgets all previous samples
+    fn reset(&mut self);
+}
+
+/// The mean of the last `A::capacity()` samples
+#[derive(Clone)]
+pub struct MovingAverage<A: Array<Item = u16>> {
+    samples: ArrayVec<A>,
+    /// Where the next sample goes once the window is full
+    next: usize,
+    sum: u32,
+}
+
+impl<A: Array<Item = u16>> MovingAverage<A> {
+    pub fn new() -> Self {
+        Self { samples: ArrayVec::new(), next: 0, sum: 0 }
+    }
+}
+
+impl<A: Array<Item = u16>> Default for MovingAverage<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: Array<Item = u16>> Filter for MovingAverage<A> {
+    /// Until the window is full this is the mean of the samples so far
+    fn update(&mut self, sample: u16) -> u16 {
+        if self.samples.is_full() {
+            self.sum -= self.samples[self.next] as u32;
+            self.samples[self.next] = sample;
+            self.next = (self.next + 1) % self.samples.len();
+        }
+        else {
+            self.samples.push(sample);
+        }
+        self.sum += sample as u32;
+
+        let count = self.samples.len() as u32;
+        ((self.sum + count / 2) / count) as u16
+    }
+
+    fn reset(&mut self) {
+        self.samples.clear();
+        self.next = 0;
+        self.sum = 0;
+    }
+}
+
+/**
+  A first order low-pass, the discrete version of an RC filter. Each sample
+  moves the output `alpha` of the way towards it. The output starts at 0 like
+  a discharged capacitor.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exponential {
+    /// Fraction with `FRACTION_BITS` bits
+    alpha: u32,
+    /// Output with `FRACTION_BITS` fraction bits
+    value: u32,
+}
+
+impl Exponential {
+    /// `alpha` is given in 1/65536ths and is limited to at most 1
+    pub fn new(alpha: u32) -> Self {
+        Self { alpha: alpha.min(ONE), value: 0 }
+    }
+
+    /**
+      A filter with the time constant, RC, of an analog filter when sampled
+      every `period`. Both are in µs. Uses alpha = T / (RC + T), which is close
+      to the exact 1 - e^(-T/RC) as long as T is much shorter than RC
+    */
+    pub fn from_time_constant(time_constant: u32, period: u32) -> Self {
+        let alpha = ((period as u64) << FRACTION_BITS) / (time_constant as u64 + period as u64);
+        Self::new(alpha as u32)
+    }
+
+    /// In 1/65536ths
+    pub fn alpha(&self) -> u32 {
+        self.alpha
+    }
+}
+
+impl Filter for Exponential {
+    fn update(&mut self, sample: u16) -> u16 {
+        let target = (sample as i64) << FRACTION_BITS;
+        let step = ((target - self.value as i64) * self.alpha as i64) >> FRACTION_BITS;
+        self.value = (self.value as i64 + step) as u32;
+        ((self.value + ONE / 2) >> FRACTION_BITS) as u16
+    }
+
+    fn reset(&mut self) {
+        self.value = 0;
+    }
+}
+
+/**
+  The median of the last `A::capacity()` samples. Windows with an even length
+  return the lower of the middle two
+*/
+#[derive(Clone)]
+pub struct Median<A: Array<Item = u16>> {
+    samples: ArrayVec<A>,
+    next: usize,
+}
+
+impl<A: Array<Item = u16>> Median<A> {
+    pub fn new() -> Self {
+        Self { samples: ArrayVec::new(), next: 0 }
+    }
+}
+
+impl<A: Array<Item = u16>> Default for Median<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: Array<Item = u16>> Filter for Median<A> {
+    fn update(&mut self, sample: u16) -> u16 {
+        if self.samples.is_full() {
+            self.samples[self.next] = sample;
+            self.next = (self.next + 1) % self.samples.len();
+        }
+        else {
+            self.samples.push(sample);
+        }
+
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+        sorted[(sorted.len() - 1) / 2]
+    }
+
+    fn reset(&mut self) {
+        self.samples.clear();
+        self.next = 0;
+    }
+}
+
+/// Spike rejection followed by smoothing, for the measured voltage and current
+#[derive(Clone)]
+pub struct Measurement {
+    spikes: Median<[u16; 3]>,
+    smoothing: Exponential,
+}
+
+impl Measurement {
+    /// For samples taken every `period` µs
+    pub fn new(period: u32) -> Self {
+        Self {
+            spikes: Median::new(),
+            smoothing: Exponential::from_time_constant(MEASUREMENT_TIME_CONSTANT, period),
+        }
+    }
+}
+
+impl Filter for Measurement {
+    fn update(&mut self, sample: u16) -> u16 {
+        let sample = self.spikes.update(sample);
+        self.smoothing.update(sample)
+    }
+
+    fn reset(&mut self) {
+        self.spikes.reset();
+        self.smoothing.reset();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate std;
+
+    use self::std::f32::consts::PI;
+
+    use adc::ADC_MAX;
+
+    // The RC filter from math/filter.py
+    const RESISTANCE: f32 = 1000.;
+    const CAPACITANCE: f32 = 1e-6;
+    /// RC in µs
+    const TIME_CONSTANT: u32 = 1_000;
+    /// Sampling period in µs, much shorter than RC
+    const PERIOD: u32 = 10;
+
+    const STEP: u16 = 4000;
+
+    /// Gain of the RC filter at `frequency`, `filter` in filter.py
+    fn rc_gain(frequency: f32) -> f32 {
+        let reactance = 1. / (2. * PI * frequency * CAPACITANCE);
+        let impedance = (RESISTANCE * RESISTANCE + reactance * reactance).sqrt();
+        reactance / impedance
+    }
+
+    /// Output of the RC filter `time` s after a step from 0 to 1
+    fn rc_step(time: f32) -> f32 {
+        1. - (-time / (RESISTANCE * CAPACITANCE)).exp()
+    }
+
+    fn rc_filter() -> Exponential {
+        Exponential::from_time_constant(TIME_CONSTANT, PERIOD)
+    }
+
+    /// Deterministic noise uniformly spread over -amplitude..amplitude
+    struct Noise {
+        seed: u32,
+    }
+
+    impl Noise {
+        fn next(&mut self, amplitude: f32) -> f32 {
+            self.seed = self.seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let unit = (self.seed >> 8) as f32 / (1 << 24) as f32;
+            (unit * 2. - 1.) * amplitude
+        }
+    }
+
+    /// Variance of the filter output relative to the variance of the input
+    fn noise_gain<F: Filter>(filter: &mut F) -> f32 {
+        const MEAN: f32 = 2048.;
+        const SAMPLES: usize = 100_000;
+        let mut noise = Noise { seed: 1 };
+        // Settle on the mean first
+        for _ in 0..1000 {
+            filter.update(MEAN as u16);
+        }
+        let (mut input, mut output) = (0., 0.);
+        for _ in 0..SAMPLES {
+            let sample = MEAN + noise.next(1000.);
+            let filtered = filter.update(sample as u16) as f32;
+            input += (sample - MEAN) * (sample - MEAN);
+            output += (filtered - MEAN) * (filtered - MEAN);
+        }
+        output / input
+    }
+
+    #[test]
+    fn exponential_step_follows_the_rc_model() {
+        let mut filter = rc_filter();
+        let mut previous = 0;
+        for n in 1..=5 * TIME_CONSTANT / PERIOD {
+            let output = filter.update(STEP);
+            assert!(output >= previous);
+            previous = output;
+
+            let expected = rc_step((n * PERIOD) as f32 * 1e-6) * STEP as f32;
+            let error = (output as f32 - expected).abs();
+            assert!(error < 0.01 * STEP as f32, "{} µs: {} vs {}", n * PERIOD, output, expected);
+        }
+    }
+
+    #[test]
+    fn exponential_settles_on_the_input() {
+        let mut filter = rc_filter();
+        for _ in 0..20 * TIME_CONSTANT / PERIOD {
+            filter.update(STEP);
+        }
+        assert_eq!(filter.update(STEP), STEP);
+        for _ in 0..20 * TIME_CONSTANT / PERIOD {
+            filter.update(0);
+        }
+        assert_eq!(filter.update(0), 0);
+    }
+
+    #[test]
+    fn exponential_attenuates_sines_like_the_rc_model() {
+        for &frequency in &[10., 159., 1_000., 5_000.] {
+            let mut filter = rc_filter();
+            let samples_per_second = 1e6 / PERIOD as f32;
+            let amplitude = 2000.;
+            let mut peak = 0.;
+            for n in 0..(samples_per_second * 0.5) as u32 {
+                let time = n as f32 / samples_per_second;
+                let sample = 2048. + amplitude * (2. * PI * frequency * time).sin();
+                let output = filter.update(sample as u16) as f32 - 2048.;
+                // Skip the start up transient
+                if time > 0.2 && output > peak {
+                    peak = output;
+                }
+            }
+            let gain = peak / amplitude;
+            let expected = rc_gain(frequency);
+            assert!((gain - expected).abs() < 0.05 * expected + 0.002, "{} Hz: {} vs {}", frequency, gain, expected);
+        }
+    }
+
+    #[test]
+    fn exponential_attenuates_noise_like_the_rc_model() {
+        // White noise up to half the sample rate through a low-pass with a
+        // noise bandwidth of 1 / 4RC
+        let expected = PERIOD as f32 / (2. * TIME_CONSTANT as f32);
+        let gain = noise_gain(&mut rc_filter());
+        assert!((gain - expected).abs() < 0.15 * expected, "{} vs {}", gain, expected);
+    }
+
+    #[test]
+    fn exponential_alpha() {
+        assert_eq!(Exponential::from_time_constant(1, 1).alpha(), ONE / 2);
+        assert_eq!(Exponential::from_time_constant(0, 1).alpha(), ONE);
+        assert_eq!(Exponential::new(2 * ONE).alpha(), ONE);
+        // An alpha of 1 passes samples straight through
+        let mut filter = Exponential::new(ONE);
+        assert_eq!(filter.update(ADC_MAX), ADC_MAX);
+        assert_eq!(filter.update(17), 17);
+    }
+
+    #[test]
+    fn moving_average_step_is_a_linear_ramp() {
+        let mut filter = MovingAverage::<[u16; 4]>::new();
+        for _ in 0..4 {
+            filter.update(0);
+        }
+        let outputs = [filter.update(400), filter.update(400), filter.update(400), filter.update(400)];
+        assert_eq!(outputs, [100, 200, 300, 400]);
+        assert_eq!(filter.update(400), 400);
+    }
+
+    #[test]
+    fn moving_average_starts_with_the_samples_so_far() {
+        let mut filter = MovingAverage::<[u16; 8]>::new();
+        assert_eq!(filter.update(ADC_MAX), ADC_MAX);
+        assert_eq!(filter.update(1), 2048);
+        filter.reset();
+        assert_eq!(filter.update(10), 10);
+    }
+
+    #[test]
+    fn moving_average_attenuates_noise() {
+        let gain = noise_gain(&mut MovingAverage::<[u16; 16]>::new());
+        assert!((gain - 1. / 16.).abs() < 0.01, "{}", gain);
+    }
+
+    #[test]
+    fn median_rejects_spikes() {
+        let mut filter = Median::<[u16; 5]>::new();
+        let trace = [1000, 4095, 1000, 1000, 1000, 0, 1000, 1000, 1000, 4095, 4095, 1000, 1000];
+        for &sample in &trace {
+            assert_eq!(filter.update(sample), 1000);
+        }
+    }
+
+    #[test]
+    fn median_passes_steps_after_half_the_window() {
+        let mut filter = Median::<[u16; 5]>::new();
+        for _ in 0..5 {
+            filter.update(0);
+        }
+        let outputs = [filter.update(STEP), filter.update(STEP), filter.update(STEP)];
+        assert_eq!(outputs, [0, 0, STEP]);
+    }
+
+    #[test]
+    fn median_of_an_even_window_is_the_lower_middle() {
+        let mut filter = Median::<[u16; 4]>::new();
+        assert_eq!(filter.update(40), 40);
+        assert_eq!(filter.update(10), 10);
+        assert_eq!(filter.update(30), 30);
+        assert_eq!(filter.update(20), 20);
+        filter.reset();
+        assert_eq!(filter.update(7), 7);
+    }
+
+    #[test]
+    fn measurement_ignores_spikes_and_settles() {
+        const CONTROL_PERIOD: u32 = 1_000;
+        let mut filter = Measurement::new(CONTROL_PERIOD);
+        for _ in 0..20 {
+            filter.update(2000);
+        }
+        assert_eq!(filter.update(2000), 2000);
+        assert_eq!(filter.update(ADC_MAX), 2000);
+        assert_eq!(filter.update(2000), 2000);
+        assert_eq!(filter.update(0), 2000);
+
+        filter.reset();
+        assert!(filter.update(2000) < 2000);
+    }
+}
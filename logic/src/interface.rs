@@ -0,0 +1,557 @@
+use menu::{Confirm, Format, Item, Node, NumberEditor, Then};
+use state::{State, PRESET_SLOTS};
+use units::{MilliAmps, MilliVolts};
+use current;
+use voltage;
+
+
+/// The actions the user can take on the keypad
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Input {
+    Digit(u8),
+    DecimalPoint,
+    Confirm,
+    /// Removes the last typed character
+    Backspace,
+    /// Removes everything typed so far
+    Clear,
+    /// Leaves the current menu without doing anything
+    Cancel,
+}
+
+/**
+  Maps the characters produced by the keypad to inputs. The digits '0' to '9'
+  are always digits.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyBindings {
+    pub decimal_point: char,
+    pub confirm: char,
+    pub backspace: char,
+    pub clear: char,
+    pub cancel: char,
+}
+
+impl KeyBindings {
+    pub fn input(&self, key: char) -> Option<Input> {
+        match key.to_digit(10) {
+            Some(digit) => Some(Input::Digit(digit as u8)),
+            None if key == self.decimal_point => Some(Input::DecimalPoint),
+            None if key == self.confirm => Some(Input::Confirm),
+            None if key == self.backspace => Some(Input::Backspace),
+            None if key == self.clear => Some(Input::Clear),
+            None if key == self.cancel => Some(Input::Cancel),
+            None => None,
+        }
+    }
+}
+
+/// Bindings for the keys in `keymap`
+pub const DEFAULT_KEY_BINDINGS: KeyBindings = KeyBindings {
+    decimal_point: '.',
+    confirm: 'a',
+    backspace: 'b',
+    clear: 'c',
+    cancel: 'x',
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Voltage(MilliVolts),
+    Current(MilliAmps),
+    OutputOn,
+    OutputOff,
+    /// Output a fixed duty, given as a fraction of the max duty, for calibration
+    CalibrationDuty(f32),
+    /// The voltage measured at the last calibration duty
+    CalibrationMeasured(MilliVolts),
+    /// Fit a new calibration from the measured points and resume normal operation
+    CalibrationDone,
+    /// Store the voltage and current setpoints in a preset slot, numbered from 1
+    StorePreset(u8),
+    /// Apply the setpoints stored in a preset slot
+    RecallPreset(u8),
+    /// Clear a latched protection fault
+    ResetProtection,
+    /// How long the current may exceed the limit before the output trips, in ms
+    OverCurrentDelay(u32),
+    /// The measured voltage above which the output trips
+    OverVoltage(MilliVolts),
+    /// How fast the output moves to a new voltage, in mV/ms
+    SlewRate(u32),
+    /// Switches the status line between the setpoints and the measurements
+    ToggleView,
+}
+
+/// Inclusive range of values accepted for a setting
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl Limits {
+    pub fn contains(&self, val: u32) -> bool {
+        self.min <= val && val <= self.max
+    }
+
+    /// The closest value to `val` within the limits
+    pub fn clamp(&self, val: u32) -> u32 {
+        val.clamp(self.min, self.max)
+    }
+}
+
+/// The quantities that can be entered on the keypad
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quantity {
+    /// Voltage setpoint in mV
+    Voltage,
+    /// Current limit in mA
+    Current,
+    /// Calibration duty in tenths of a percent
+    CalibrationDuty,
+    /// Voltage measured during calibration in mV
+    CalibrationVoltage,
+}
+
+impl Quantity {
+    pub fn limits(&self) -> Limits {
+        match *self {
+            Quantity::Voltage => Limits { min: voltage::MIN_VOLTAGE.0, max: voltage::MAX_VOLTAGE.0 },
+            Quantity::Current => Limits { min: 0, max: current::MAX_CURRENT_MA.0 },
+            Quantity::CalibrationDuty => Limits { min: 0, max: 1000 },
+            Quantity::CalibrationVoltage => Limits { min: 0, max: 25000 },
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match *self {
+            Quantity::Voltage | Quantity::CalibrationVoltage => " mV",
+            Quantity::Current => " mA",
+            Quantity::CalibrationDuty => "/1000",
+        }
+    }
+
+    /**
+      Returns the command which sets the quantity to an entered value, or
+      `None` if the value is outside the limits. `val` is in thousandths of the
+      unit. Only the calibration duty is converted to a fraction, the rest are
+      kept as they are.
+    */
+    pub fn check(self, val: u32) -> Option<Command> {
+        if !self.limits().contains(val) {
+            return None;
+        }
+        let command = match self {
+            Quantity::Voltage => Command::Voltage(MilliVolts(val)),
+            Quantity::Current => Command::Current(MilliAmps(val)),
+            Quantity::CalibrationDuty => Command::CalibrationDuty(val as f32 / 1000.),
+            Quantity::CalibrationVoltage => Command::CalibrationMeasured(MilliVolts(val)),
+        };
+        Some(command)
+    }
+}
+
+
+static VOLTAGE: Node = Node::Number(NumberEditor {
+    label: "",
+    quantity: Quantity::Voltage,
+    format: Format::Decimal("V"),
+    confirm: true,
+    then: Then::Back,
+    on_cancel: None,
+});
+
+static CURRENT: Node = Node::Number(NumberEditor {
+    label: "",
+    quantity: Quantity::Current,
+    format: Format::Decimal("A"),
+    confirm: true,
+    then: Then::Back,
+    on_cancel: None,
+});
+
+static OUTPUT_ENABLE: Node = Node::Toggle {
+    label: "Output",
+    value: State::software_enabled,
+    on: Command::OutputOn,
+    off: Command::OutputOff,
+};
+
+/// Clears a latched protection fault, the output then has to be turned on again
+static RESET_PROTECTION: Node = Node::Confirm {
+    prompt: "Reset? 1:y 2:n",
+    command: Command::ResetProtection,
+};
+
+static OUTPUT: Node = Node::Menu(&[
+    Item { label: "Out", node: &OUTPUT_ENABLE },
+    Item { label: "Reset", node: &RESET_PROTECTION },
+]);
+
+/**
+  Calibration alternates between picking a duty and typing the voltage
+  measured at it. Leaving the duty editor fits the new calibration.
+*/
+static CALIBRATION_DUTY: Node = Node::Number(NumberEditor {
+    label: "Duty ",
+    quantity: Quantity::CalibrationDuty,
+    format: Format::Integer("/1000"),
+    confirm: false,
+    then: Then::Open(&CALIBRATION_MEASURED),
+    on_cancel: Some(Command::CalibrationDone),
+});
+
+static CALIBRATION_MEASURED: Node = Node::Number(NumberEditor {
+    label: "Meas ",
+    quantity: Quantity::CalibrationVoltage,
+    format: Format::Decimal("V"),
+    confirm: false,
+    then: Then::Back,
+    on_cancel: None,
+});
+
+static STORE_PRESET: Node = Node::Slot {
+    label: "Store to",
+    count: PRESET_SLOTS as u8,
+    command: Command::StorePreset,
+    confirm: Confirm::Never,
+};
+
+/// Recalling a preset shows the stored setpoints
+static RECALL_PRESET: Node = Node::Slot {
+    label: "Recall",
+    count: PRESET_SLOTS as u8,
+    command: Command::RecallPreset,
+    confirm: Confirm::IfOutputEnabled,
+};
+
+static MEMORY: Node = Node::Menu(&[
+    Item { label: "Store", node: &STORE_PRESET },
+    Item { label: "Recall", node: &RECALL_PRESET },
+]);
+
+/// The menu shown when the supply starts
+pub static MAIN_MENU: Node = Node::Menu(&[
+    Item { label: "V", node: &VOLTAGE },
+    Item { label: "A", node: &CURRENT },
+    Item { label: "IO", node: &OUTPUT },
+    Item { label: "C", node: &CALIBRATION_DUTY },
+    Item { label: "M", node: &MEMORY },
+]);
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use arrayvec::ArrayString;
+
+    use menu::{Menu, LINE_LENGTH};
+    use state::{State, View};
+
+    const START: &str = "1V 2A 3IO 4C 5M";
+    const CONFIRM: &str = "Confirm 1:y 2:n";
+
+    /**
+      Types `seq`, applying each command to `state`. Returns the display and
+      the last command
+    */
+    fn run_on_state(seq: &str, state: &mut State) -> (ArrayString<[u8; 32]>, Option<Command>) {
+        let mut menu = Menu::new(&MAIN_MENU);
+        let mut last_cmd = None;
+        for input in seq.chars() {
+            let input = DEFAULT_KEY_BINDINGS.input(input).unwrap();
+            let (new_menu, new_cmd) = menu.update(input, state);
+            menu = new_menu;
+            if let Some(ref command) = new_cmd {
+                state.apply_command(command.clone());
+            }
+            last_cmd = new_cmd;
+        }
+
+        (menu.get_display().unwrap(), last_cmd)
+    }
+
+    fn run_input_sequence(seq: &str) -> (ArrayString<[u8; 32]>, Option<Command>) {
+        run_on_state(seq, &mut State::new(false))
+    }
+
+    /// Checks the display and the last command after typing `seq`
+    fn check(seq: &str, display: &str, command: Option<Command>) {
+        let (result_display, result_command) = run_input_sequence(seq);
+        assert_eq!((result_display.as_str(), result_command), (display, command), "{}", seq);
+    }
+
+    /// A supply with 5 V, 0.5 A stored in slot 2
+    fn state_with_preset(output_enabled: bool) -> State {
+        let mut state = State::new(false);
+        state.set_output_switch_state(output_enabled);
+        run_on_state("15a120.5a1512", &mut state);
+        state.apply_command(Command::Voltage(MilliVolts(12_000)));
+        state
+    }
+
+    fn display(seq: &str) -> ArrayString<[u8; 32]> {
+        run_input_sequence(seq).0
+    }
+
+    #[test]
+    fn confirm_on_a_menu_toggles_the_view() {
+        check("a", START, Some(Command::ToggleView));
+        check("5a", "1:Store 2:Recall", Some(Command::ToggleView));
+        let mut state = State::new(false);
+        run_on_state("a", &mut state);
+        assert_eq!(state.view(), View::Measurements);
+    }
+
+    /// Keys typing `thousandths` as a decimal number in whole units, "12.345"
+    fn decimal_keys(thousandths: u32) -> ArrayString<[u8; 32]> {
+        let mut buffer = itoa::Buffer::new();
+        let mut keys = ArrayString::from(buffer.format(thousandths / 1000)).unwrap();
+        keys.push('.');
+        let fraction = thousandths % 1000;
+        for digit in &[fraction / 100, fraction / 10 % 10, fraction % 10] {
+            keys.push_str(buffer.format(*digit));
+        }
+        keys
+    }
+
+    /// Types `value` into the editor opened by `key` and confirms it
+    fn enter(key: &str, value: u32, state: &mut State) -> Option<Command> {
+        let mut seq = ArrayString::<[u8; 32]>::from(key).unwrap();
+        seq.push_str(&decimal_keys(value));
+        seq.push_str("a1");
+        run_on_state(&seq, state).1
+    }
+
+    #[test]
+    fn every_entered_voltage_is_exact() {
+        let limits = Quantity::Voltage.limits();
+        let mut state = State::new(false);
+        for millivolts in limits.min..=limits.max {
+            let expected = MilliVolts(millivolts);
+            assert_eq!(enter("1", millivolts, &mut state), Some(Command::Voltage(expected)));
+            assert_eq!(state.voltage(), expected);
+        }
+    }
+
+    #[test]
+    fn every_entered_current_is_exact() {
+        let limits = Quantity::Current.limits();
+        let mut state = State::new(false);
+        for milliamps in limits.min..=limits.max {
+            let expected = MilliAmps(milliamps);
+            assert_eq!(enter("2", milliamps, &mut state), Some(Command::Current(expected)));
+            assert_eq!(state.current_limit(), expected);
+        }
+    }
+
+    #[test]
+    fn entered_values_are_not_rounded_down() {
+        let mut state = State::new(false);
+        assert_eq!(decimal_keys(1234).as_str(), "1.234");
+        enter("1", 12_345, &mut state);
+        enter("2", 1234, &mut state);
+        assert_eq!(state.voltage(), MilliVolts(12_345));
+        assert_eq!(state.current_limit(), MilliAmps(1234));
+    }
+
+    #[test]
+    fn voltage_input() {
+        check("1.12345a1", START, Some(Command::Voltage(MilliVolts(12_345))));
+    }
+    #[test]
+    fn decimal_voltage_input() {
+        check("112.5a1", START, Some(Command::Voltage(MilliVolts(12_500))));
+        check("15a1", START, Some(Command::Voltage(MilliVolts(5000))));
+    }
+    #[test]
+    fn current_input() {
+        check("2.2", "2 mA", None);
+        check("2.2a", CONFIRM, None);
+        check("2.2a1", START, Some(Command::Current(MilliAmps(2))));
+        check("2.234a1", START, Some(Command::Current(MilliAmps(234))));
+        check("20.25a1", START, Some(Command::Current(MilliAmps(250))));
+    }
+    #[test]
+    fn aborted_voltage() {
+        check("1.12345a2", START, None);
+    }
+    #[test]
+    fn aborted_current() {
+        check("2.234a2", START, None);
+    }
+
+    #[test]
+    fn output_menu() {
+        check("3", "1:Out 2:Reset", None);
+        check("31", "Output On 1:Off", None);
+        check("311", "1:Out 2:Reset", Some(Command::OutputOff));
+        check("3111", "Output Off 1:On", None);
+        check("31111", "1:Out 2:Reset", Some(Command::OutputOn));
+        check("32", "Reset? 1:y 2:n", None);
+        check("321", "1:Out 2:Reset", Some(Command::ResetProtection));
+        check("322", "1:Out 2:Reset", None);
+        check("33", "1:Out 2:Reset", None);
+    }
+
+    #[test]
+    fn calibration_points() {
+        check("4250a", "Meas 0 V", Some(Command::CalibrationDuty(0.25)));
+        check("4250a5.432a", "Duty 0/1000", Some(Command::CalibrationMeasured(MilliVolts(5432))));
+        check("4250a5.432a750a15ax", START, Some(Command::CalibrationDone));
+    }
+
+    #[test]
+    fn discarded_calibration_measurement() {
+        check("4250a54x", "Duty 0/1000", None);
+    }
+
+    #[test]
+    fn voltage_limits() {
+        check("1.1290a", "Min 1291 mV", None);
+        check("11.291a1", START, Some(Command::Voltage(MilliVolts(1291))));
+        check("120.241a1", START, Some(Command::Voltage(MilliVolts(20_241))));
+        check("120.242a", "Max 20241 mV", None);
+        check("1a", "Min 1291 mV", None);
+    }
+
+    #[test]
+    fn current_limits() {
+        check("20a1", START, Some(Command::Current(MilliAmps(0))));
+        check("23a1", START, Some(Command::Current(MilliAmps(3000))));
+        check("23.001a", "Max 3000 mA", None);
+    }
+
+    #[test]
+    fn calibration_limits() {
+        check("41000a", "Meas 0 V", Some(Command::CalibrationDuty(1.)));
+        check("41001a", "Max 1000/1000", None);
+        check("4500a25a", "Duty 0/1000", Some(Command::CalibrationMeasured(MilliVolts(25_000))));
+        check("4500a25.001a", "Max 25000 mV", None);
+        check("4500a25.001a5", "Meas 0 V", None);
+    }
+
+    #[test]
+    fn entry_saturates() {
+        check("199999999999a", "Max 20241 mV", None);
+        check("1.99999999999a", "Max 20241 mV", None);
+        check("41000000a", "Max 1000/1000", None);
+    }
+
+    #[test]
+    fn out_of_range_returns_to_input() {
+        check("125a", "Max 20241 mV", None);
+        check("125ab", "0 V", None);
+        check("125ab5a1", START, Some(Command::Voltage(MilliVolts(5000))));
+        check("29.999a1", "0 A", None);
+    }
+
+    #[test]
+    fn entry_display() {
+        assert_eq!(display("1").as_str(), "0 V");
+        assert_eq!(display("112.").as_str(), "12. V");
+        assert_eq!(display("112.5").as_str(), "12.5 V");
+        assert_eq!(display("1.500").as_str(), "500 mV");
+        assert_eq!(display("20.25").as_str(), "0.25 A");
+        assert_eq!(display("4250a5.4").as_str(), "Meas 5.4 V");
+    }
+
+    #[test]
+    fn displays_fit_on_a_line() {
+        for seq in &["", "1", "2", "3", "4", "5", "51", "52", "1a", "11a", "4250a5.432"] {
+            assert!(display(seq).len() <= LINE_LENGTH, "{}", display(seq));
+        }
+    }
+
+    #[test]
+    fn backspace() {
+        assert_eq!(display("112.5b").as_str(), "12. V");
+        assert_eq!(display("112.5bb").as_str(), "12 V");
+        assert_eq!(display("112.5bbb").as_str(), "1 V");
+        assert_eq!(display("112.5bbbbb").as_str(), "0 V");
+        check("112.5bbb3a1", START, Some(Command::Voltage(MilliVolts(13_000))));
+        assert_eq!(display("4257b").as_str(), "Duty 25/1000");
+    }
+
+    #[test]
+    fn backspace_on_empty_entry_stays() {
+        check("1b", "0 V", None);
+    }
+
+    #[test]
+    fn clear_entry() {
+        check("112.5c", "0 V", None);
+        assert_eq!(display("1.500c").as_str(), "0 mV");
+        assert_eq!(display("4257c").as_str(), "Duty 0/1000");
+        check("112.5c5a1", START, Some(Command::Voltage(MilliVolts(5000))));
+    }
+
+    #[test]
+    fn cancel() {
+        check("112.5x", START, None);
+        check("20.5x", START, None);
+        check("3x", START, None);
+        check("15ax", START, None);
+        check("x", START, None);
+        check("4x", START, Some(Command::CalibrationDone));
+    }
+
+    #[test]
+    fn store_preset() {
+        check("5", "1:Store 2:Recall", None);
+        check("51", "Store to 1-4", None);
+        check("513", "1:Store 2:Recall", Some(Command::StorePreset(3)));
+        check("515", "Store to 1-4", None);
+    }
+
+    #[test]
+    fn recall_preset_with_output_off() {
+        let mut state = state_with_preset(false);
+        let (display, command) = run_on_state("522", &mut state);
+        assert_eq!(display.as_str(), "5.00V 0.50A");
+        assert_eq!(command, Some(Command::RecallPreset(2)));
+        assert_eq!(state.get_display().unwrap().as_str(), "5.00V 0.50A Off");
+        // Any key leaves the recalled values
+        assert_eq!(run_on_state("5227", &mut state).0.as_str(), "1:Store 2:Recall");
+    }
+
+    #[test]
+    fn recall_preset_with_output_on() {
+        let mut state = state_with_preset(true);
+        let (display, command) = run_on_state("522", &mut state);
+        assert_eq!(display.as_str(), "5.00V 0.50A 1:y");
+        assert_eq!(command, None);
+        assert_eq!(run_on_state("5222", &mut state), (ArrayString::from("1:Store 2:Recall").unwrap(), None));
+        assert_eq!(state.get_display().unwrap().as_str(), "12.00V 0.50A CV");
+        assert_eq!(run_on_state("5221", &mut state).1, Some(Command::RecallPreset(2)));
+        assert_eq!(state.get_display().unwrap().as_str(), "5.00V 0.50A CV");
+    }
+
+    #[test]
+    fn recall_empty_preset() {
+        let mut state = state_with_preset(true);
+        let (display, command) = run_on_state("521", &mut state);
+        assert_eq!((display.as_str(), command), ("Empty slot", None));
+        assert_eq!(run_on_state("521a", &mut state).0.as_str(), "1:Store 2:Recall");
+    }
+
+    #[test]
+    fn custom_bindings() {
+        let bindings = KeyBindings {
+            decimal_point: '*',
+            confirm: '#',
+            backspace: '<',
+            clear: 'C',
+            cancel: 'D',
+        };
+        assert_eq!(bindings.input('7'), Some(Input::Digit(7)));
+        assert_eq!(bindings.input('*'), Some(Input::DecimalPoint));
+        assert_eq!(bindings.input('#'), Some(Input::Confirm));
+        assert_eq!(bindings.input('<'), Some(Input::Backspace));
+        assert_eq!(bindings.input('C'), Some(Input::Clear));
+        assert_eq!(bindings.input('D'), Some(Input::Cancel));
+        assert_eq!(bindings.input('a'), None);
+    }
+}
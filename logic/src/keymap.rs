@@ -0,0 +1,214 @@
+/*!
+  Layout of the keypad.
+
+  Keymaps are written the way the keypad is printed, as rows of keys from top
+  to bottom. `Wiring` describes how the keypad is connected to `Keypad` so
+  that scanned coordinates can be looked up.
+*/
+
+use debounce::{EventKind, KeyEvent};
+
+/// How the keypad matrix is connected to a `Keypad`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Wiring {
+    /// The keypad rows are the `Keypad` inputs and the columns are the outputs
+    RowInputs,
+    /// The keypad columns are the `Keypad` inputs and the rows are the outputs
+    ColumnInputs,
+}
+
+/// Values for each key of a keypad, stored as `keys[row][column]`
+#[derive(Clone, Copy, Debug)]
+pub struct Keymap<'a, T: 'a> {
+    keys: &'a [&'a [T]],
+    wiring: Wiring,
+}
+
+impl<'a, T: Copy + 'a> Keymap<'a, T> {
+    pub const fn new(keys: &'a [&'a [T]], wiring: Wiring) -> Self {
+        Self { keys, wiring }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn columns(&self) -> usize {
+        self.keys.iter().map(|row| row.len()).max().unwrap_or(0)
+    }
+
+    pub fn wiring(&self) -> Wiring {
+        self.wiring
+    }
+
+    /// The key at `row` and `column` as printed, `None` if there is no such key
+    pub fn at(&self, row: usize, column: usize) -> Option<T> {
+        self.keys.get(row).and_then(|keys| keys.get(column)).cloned()
+    }
+
+    /**
+      The key at a coordinate from `Keypad::read_all_coords`, `None` if the
+      coordinate is outside the keymap
+    */
+    pub fn key(&self, (input, output): (u8, u8)) -> Option<T> {
+        let (input, output) = (input as usize, output as usize);
+        match self.wiring {
+            Wiring::RowInputs => self.at(input, output),
+            Wiring::ColumnInputs => self.at(output, input),
+        }
+    }
+
+    pub fn contains(&self, value: &T) -> bool
+        where T: PartialEq
+    {
+        self.keys.iter().any(|row| row.contains(value))
+    }
+}
+
+
+pub const KEYMAP_DATA: [[char; 3]; 4] =
+    [ ['1', '2', '3']
+    , ['4', '5', '6']
+    , ['7', '8', '9']
+    , ['a', '0', 'b']
+    ];
+
+
+const KEYMAP_ROWS: [&[char]; 4] =
+    [ &KEYMAP_DATA[0]
+    , &KEYMAP_DATA[1]
+    , &KEYMAP_DATA[2]
+    , &KEYMAP_DATA[3]
+    ];
+
+/// The keypad of the supply. Its columns are connected to the `Keypad` inputs
+pub const KEYMAP: Keymap<'static, char> = Keymap::new(&KEYMAP_ROWS, Wiring::ColumnInputs);
+
+
+/**
+  Characters produced by holding a key down. Keys with `None` have no long press action.
+  Holding enter clears the entry, holding 0 types a decimal point and holding
+  backspace cancels.
+*/
+pub const LONG_PRESS_KEYMAP_DATA: [[Option<char>; 3]; 4] =
+    [ [None, None, None]
+    , [None, None, None]
+    , [None, None, None]
+    , [Some('c'), Some('.'), Some('x')]
+    ];
+
+
+const LONG_PRESS_KEYMAP_ROWS: [&[Option<char>]; 4] =
+    [ &LONG_PRESS_KEYMAP_DATA[0]
+    , &LONG_PRESS_KEYMAP_DATA[1]
+    , &LONG_PRESS_KEYMAP_DATA[2]
+    , &LONG_PRESS_KEYMAP_DATA[3]
+    ];
+
+pub const LONG_PRESS_KEYMAP: Keymap<'static, Option<char>> =
+    Keymap::new(&LONG_PRESS_KEYMAP_ROWS, Wiring::ColumnInputs);
+
+
+/**
+  The character typed by a key event. Keys without a long press action type
+  as soon as they are pressed. Other keys type when released, or type their
+  long press character once held for `long_press` milliseconds.
+*/
+pub fn event_char(event: &KeyEvent, long_press: u32) -> Option<char> {
+    let key_char = KEYMAP.key(event.key)?;
+    match (event.kind, LONG_PRESS_KEYMAP.key(event.key).and_then(|c| c)) {
+        (EventKind::Press, None) => Some(key_char),
+        (EventKind::LongPress, Some(long_press_char)) => Some(long_press_char),
+        (EventKind::Release { held }, Some(_)) if held < long_press => Some(key_char),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(key: (u8, u8), kind: EventKind) -> KeyEvent {
+        KeyEvent { key, kind, time: 0 }
+    }
+
+    const HEX_KEYPAD_ROWS: [&[char]; 4] =
+        [ &['1', '2', '3', 'A']
+        , &['4', '5', '6', 'B']
+        , &['7', '8', '9', 'C']
+        , &['*', '0', '#', 'D']
+        ];
+
+    #[test]
+    fn dimensions() {
+        assert_eq!((KEYMAP.rows(), KEYMAP.columns()), (4, 3));
+        let hex = Keymap::new(&HEX_KEYPAD_ROWS, Wiring::RowInputs);
+        assert_eq!((hex.rows(), hex.columns()), (4, 4));
+    }
+
+    #[test]
+    fn lookup_as_printed() {
+        assert_eq!(KEYMAP.at(0, 0), Some('1'));
+        assert_eq!(KEYMAP.at(0, 2), Some('3'));
+        assert_eq!(KEYMAP.at(2, 0), Some('7'));
+        assert_eq!(KEYMAP.at(3, 1), Some('0'));
+    }
+
+    #[test]
+    fn controller_wiring() {
+        // The controller scans the keypad columns as inputs, so coordinates
+        // are (column, row)
+        assert_eq!(KEYMAP.key((0, 0)), Some('1'));
+        assert_eq!(KEYMAP.key((2, 0)), Some('3'));
+        assert_eq!(KEYMAP.key((0, 2)), Some('7'));
+        assert_eq!(KEYMAP.key((1, 3)), Some('0'));
+        assert_eq!(LONG_PRESS_KEYMAP.key((1, 3)), Some(Some('.')));
+        assert_eq!(LONG_PRESS_KEYMAP.key((0, 0)), Some(None));
+    }
+
+    #[test]
+    fn row_input_wiring() {
+        let hex = Keymap::new(&HEX_KEYPAD_ROWS, Wiring::RowInputs);
+        assert_eq!(hex.key((0, 3)), Some('A'));
+        assert_eq!(hex.key((3, 0)), Some('*'));
+        assert_eq!(hex.key((3, 3)), Some('D'));
+        let hex = Keymap::new(&HEX_KEYPAD_ROWS, Wiring::ColumnInputs);
+        assert_eq!(hex.key((0, 3)), Some('*'));
+        assert_eq!(hex.key((3, 0)), Some('A'));
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        assert_eq!(KEYMAP.key((3, 0)), None);
+        assert_eq!(KEYMAP.key((0, 4)), None);
+        assert_eq!(KEYMAP.at(0, 3), None);
+        assert_eq!(KEYMAP.at(4, 0), None);
+        assert_eq!(event_char(&event((3, 3), EventKind::Press), 500), None);
+    }
+
+    #[test]
+    fn ragged_keymap() {
+        let rows: [&[char]; 2] = [&['1', '2'], &['3']];
+        let keymap = Keymap::new(&rows, Wiring::RowInputs);
+        assert_eq!(keymap.columns(), 2);
+        assert_eq!(keymap.key((1, 0)), Some('3'));
+        assert_eq!(keymap.key((1, 1)), None);
+    }
+
+    #[test]
+    fn plain_keys_type_on_press() {
+        assert_eq!(event_char(&event((0, 0), EventKind::Press), 500), Some('1'));
+        assert_eq!(event_char(&event((0, 0), EventKind::LongPress), 500), None);
+        assert_eq!(event_char(&event((0, 0), EventKind::Release { held: 10 }), 500), None);
+    }
+
+    #[test]
+    fn long_press_keys_type_on_release_or_long_press() {
+        // The 0 key
+        assert_eq!(event_char(&event((1, 3), EventKind::Press), 500), None);
+        assert_eq!(event_char(&event((1, 3), EventKind::Release { held: 100 }), 500), Some('0'));
+        assert_eq!(event_char(&event((1, 3), EventKind::LongPress), 500), Some('.'));
+        assert_eq!(event_char(&event((1, 3), EventKind::Release { held: 600 }), 500), None);
+    }
+}
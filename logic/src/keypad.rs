@@ -0,0 +1,368 @@
+// The HAL used by the controller implements the original, infallible pin traits
+// which newer embedded-hal releases mark as deprecated
+#![allow(deprecated)]
+
+use hal::digital::{OutputPin, InputPin};
+
+use arrayvec::ArrayVec;
+
+use core::borrow::{BorrowMut, Borrow};
+
+/// The most keys a `Scan` can hold
+pub const MAX_SCAN_KEYS: usize = 16;
+
+/// How much a scan can be trusted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rollover {
+    /// Every key that is down was read and none of them can be a phantom key
+    Full,
+    /**
+      Some keys form a rectangle in the matrix. Without diodes, three keys in
+      a rectangle make the fourth corner read as pressed, so any of the four
+      may be a phantom key
+    */
+    Ghosted,
+    /// More keys were down than the scan can hold
+    Overflow,
+}
+
+/// The keys read by one scan of the matrix
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scan {
+    keys: ArrayVec<[(u8, u8); MAX_SCAN_KEYS]>,
+    /// Bit `n` is set if `keys[n]` is part of a rectangle
+    ghosted: u16,
+    overflow: bool,
+}
+
+impl Scan {
+    /**
+      Builds a scan from the coordinates read from a matrix, flagging keys that
+      may be phantom keys. Keys beyond `MAX_SCAN_KEYS` are dropped.
+    */
+    pub fn new(keys: &[(u8, u8)]) -> Self {
+        let overflow = keys.len() > MAX_SCAN_KEYS;
+        let keys = keys.iter().cloned().take(MAX_SCAN_KEYS).collect::<ArrayVec<[_; MAX_SCAN_KEYS]>>();
+
+        let index = |key| keys.iter().position(|&other| other == key);
+        let mut ghosted = 0;
+        for (a, &(row, first_col)) in keys.iter().enumerate() {
+            for (b, &(_, second_col)) in keys.iter().enumerate()
+                .filter(|&(_, &(other_row, other_col))| other_row == row && other_col > first_col)
+            {
+                for (c, &(other_row, _)) in keys.iter().enumerate()
+                    .filter(|&(_, &(other_row, col))| other_row != row && col == first_col)
+                {
+                    if let Some(d) = index((other_row, second_col)) {
+                        ghosted |= 1 << a | 1 << b | 1 << c | 1 << d;
+                    }
+                }
+            }
+        }
+
+        Self { keys, ghosted, overflow }
+    }
+
+    /// All keys that read as pressed, including possible phantom keys
+    pub fn keys(&self) -> &[(u8, u8)] {
+        &self.keys
+    }
+
+    pub fn rollover(&self) -> Rollover {
+        if self.overflow {
+            Rollover::Overflow
+        }
+        else if self.ghosted != 0 {
+            Rollover::Ghosted
+        }
+        else {
+            Rollover::Full
+        }
+    }
+
+    /// Returns true if `key` read as pressed but may be a phantom key
+    pub fn is_ghosted(&self, key: (u8, u8)) -> bool {
+        self.keys.iter()
+            .position(|&other| other == key)
+            .map(|i| self.ghosted & 1 << i != 0)
+            .unwrap_or(false)
+    }
+
+    /// The keys that are certainly pressed
+    pub fn certain_keys<'a>(&'a self) -> impl Iterator<Item = (u8, u8)> + 'a {
+        self.keys.iter()
+            .enumerate()
+            .filter(move |&(i, _)| self.ghosted & 1 << i == 0)
+            .map(|(_, &key)| key)
+    }
+}
+
+pub struct Keypad<R, C, I, O>
+where R: Borrow<[I]>,
+      C: BorrowMut<[O]>,
+      I: InputPin,
+      O: OutputPin,
+{
+    columns: C,
+    rows: R,
+    _phantom: ::core::marker::PhantomData<(I, O)>,
+}
+
+impl<R, C, I, O> Keypad<R, C, I, O>
+where R: Borrow<[I]>,
+      C: BorrowMut<[O]>,
+      I: InputPin,
+      O: OutputPin,
+{
+    pub fn new(rows: R, columns: C) -> Self {
+        Self {
+            rows,
+            columns,
+            _phantom: ::core::marker::PhantomData
+        }
+    }
+    pub fn read_all_coords(&mut self, buffer: &mut [(u8, u8)]) -> usize {
+        // Set all the columns to low
+        for col in self.columns.borrow_mut() {
+            col.set_low();
+        }
+
+        let mut current_index = 0;
+
+        for (ci, col) in self.columns.borrow_mut().iter_mut().enumerate() {
+            // Set the column to high
+            col.set_high();
+            for (ri, row) in self.rows.borrow().iter().enumerate() {
+                // Read the pins
+                if row.is_high() {
+                    buffer[current_index] = (ri as u8, ci as u8);
+                    current_index += 1;
+                }
+
+                if current_index >= buffer.len() {
+                    col.set_low();
+                    return current_index;
+                }
+            }
+            // Reset the column
+            col.set_low();
+        }
+
+        current_index
+    }
+
+    /// Reads every pressed key and flags keys that may be phantom keys
+    pub fn scan(&mut self) -> Scan {
+        // One more than a scan holds so that overflow can be detected
+        let mut buffer = [(0, 0); MAX_SCAN_KEYS + 1];
+        let amount = self.read_all_coords(&mut buffer);
+        Scan::new(&buffer[..amount])
+    }
+
+    pub fn read_first_key(&mut self) -> Option<(u8, u8)> {
+        let mut buffer = [(0,0)];
+        let amount = self.read_all_coords(&mut buffer);
+        if amount != 0 {
+            Some(buffer[0])
+        }
+        else {
+            None
+        }
+    }
+}
+
+
+
+/// A simulated key matrix for tests of keypad scanning
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+
+    use core::cell::Cell;
+
+    pub const ROWS: usize = 3;
+    pub const COLS: usize = 4;
+
+    /**
+      A key matrix without diodes where pressed keys connect rows to columns.
+      A row reads high if a path of pressed keys connects it to the high
+      column, so three keys in a rectangle make the fourth corner read high.
+    */
+    pub struct Matrix {
+        pub high_column: Cell<Option<usize>>,
+        pressed: Cell<[[bool; COLS]; ROWS]>,
+    }
+
+    impl Matrix {
+        pub fn new(keys: &[(usize, usize)]) -> Self {
+            let matrix = Self {
+                high_column: Cell::new(None),
+                pressed: Cell::new([[false; COLS]; ROWS]),
+            };
+            matrix.set_pressed(keys);
+            matrix
+        }
+
+        /// Replaces the set of pressed keys
+        pub fn set_pressed(&self, keys: &[(usize, usize)]) {
+            let mut pressed = [[false; COLS]; ROWS];
+            for &(row, col) in keys {
+                pressed[row][col] = true;
+            }
+            self.pressed.set(pressed);
+        }
+    }
+
+    pub struct Column<'a> {
+        matrix: &'a Matrix,
+        index: usize,
+    }
+
+    impl<'a> OutputPin for Column<'a> {
+        fn set_high(&mut self) {
+            self.matrix.high_column.set(Some(self.index));
+        }
+        fn set_low(&mut self) {
+            if self.matrix.high_column.get() == Some(self.index) {
+                self.matrix.high_column.set(None);
+            }
+        }
+    }
+
+    pub struct Row<'a> {
+        matrix: &'a Matrix,
+        index: usize,
+    }
+
+    impl<'a> InputPin for Row<'a> {
+        fn is_high(&self) -> bool {
+            let pressed = self.matrix.pressed.get();
+            let mut rows = [false; ROWS];
+            let mut columns = [false; COLS];
+            match self.matrix.high_column.get() {
+                Some(col) => columns[col] = true,
+                None => return false
+            }
+            // Spread the high level through pressed keys until nothing changes
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for row in 0..ROWS {
+                    for col in 0..COLS {
+                        if pressed[row][col] && rows[row] != columns[col] {
+                            rows[row] = true;
+                            columns[col] = true;
+                            changed = true;
+                        }
+                    }
+                }
+            }
+            rows[self.index]
+        }
+        fn is_low(&self) -> bool {
+            !self.is_high()
+        }
+    }
+
+    pub type TestKeypad<'a> = Keypad<[Row<'a>; ROWS], [Column<'a>; COLS], Row<'a>, Column<'a>>;
+
+    pub fn keypad(matrix: &Matrix) -> TestKeypad<'_> {
+        Keypad::new(
+            [
+                Row { matrix, index: 0 },
+                Row { matrix, index: 1 },
+                Row { matrix, index: 2 },
+            ],
+            [
+                Column { matrix, index: 0 },
+                Column { matrix, index: 1 },
+                Column { matrix, index: 2 },
+                Column { matrix, index: 3 },
+            ]
+        )
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::mock::{Matrix, keypad};
+
+    #[test]
+    fn no_keys_pressed() {
+        let matrix = Matrix::new(&[]);
+        assert_eq!(keypad(&matrix).read_first_key(), None);
+    }
+
+    #[test]
+    fn single_key() {
+        let matrix = Matrix::new(&[(2, 1)]);
+        assert_eq!(keypad(&matrix).read_first_key(), Some((2, 1)));
+        assert_eq!(matrix.high_column.get(), None);
+    }
+
+    #[test]
+    fn all_coords_in_column_order() {
+        let matrix = Matrix::new(&[(0, 3), (1, 0), (2, 0)]);
+        let mut buffer = [(0, 0); 4];
+        let amount = keypad(&matrix).read_all_coords(&mut buffer);
+        assert_eq!(&buffer[..amount], &[(1, 0), (2, 0), (0, 3)]);
+    }
+
+    #[test]
+    fn full_buffer_stops_scanning() {
+        let matrix = Matrix::new(&[(0, 0), (1, 0), (2, 2)]);
+        let mut buffer = [(0, 0); 2];
+        let amount = keypad(&matrix).read_all_coords(&mut buffer);
+        assert_eq!(amount, 2);
+        assert_eq!(buffer, [(0, 0), (1, 0)]);
+        assert_eq!(matrix.high_column.get(), None);
+    }
+
+    #[test]
+    fn three_keys_in_a_rectangle_are_ghosted() {
+        let matrix = Matrix::new(&[(0, 0), (0, 2), (1, 0)]);
+        let scan = keypad(&matrix).scan();
+        assert_eq!(scan.keys(), &[(0, 0), (1, 0), (0, 2), (1, 2)]);
+        assert_eq!(scan.rollover(), Rollover::Ghosted);
+        for &key in scan.keys() {
+            assert!(scan.is_ghosted(key));
+        }
+        assert_eq!(scan.certain_keys().count(), 0);
+    }
+
+    #[test]
+    fn keys_outside_the_rectangle_are_certain() {
+        let matrix = Matrix::new(&[(0, 0), (0, 2), (1, 0), (2, 3)]);
+        let scan = keypad(&matrix).scan();
+        assert_eq!(scan.rollover(), Rollover::Ghosted);
+        assert!(!scan.is_ghosted((2, 3)));
+        assert!(scan.is_ghosted((1, 2)));
+        assert!(scan.certain_keys().eq([(2, 3)].iter().cloned()));
+    }
+
+    #[test]
+    fn keys_in_one_row_are_not_ghosted() {
+        let matrix = Matrix::new(&[(0, 0), (0, 1), (0, 2), (2, 3)]);
+        let scan = keypad(&matrix).scan();
+        assert_eq!(scan.rollover(), Rollover::Full);
+        assert_eq!(scan.certain_keys().count(), 4);
+    }
+
+    #[test]
+    fn rollover_of_two_keys() {
+        let matrix = Matrix::new(&[(0, 1), (2, 3)]);
+        let scan = keypad(&matrix).scan();
+        assert_eq!(scan.rollover(), Rollover::Full);
+        assert_eq!(scan.keys(), &[(0, 1), (2, 3)]);
+    }
+
+    #[test]
+    fn too_many_keys_overflow() {
+        let keys = [(0, 0); MAX_SCAN_KEYS + 1];
+        let scan = Scan::new(&keys);
+        assert_eq!(scan.rollover(), Rollover::Overflow);
+        assert_eq!(scan.keys().len(), MAX_SCAN_KEYS);
+    }
+}
@@ -0,0 +1,32 @@
+/*!
+  Hardware independent parts of the controller. Everything in here builds
+  without `std` so it can run on the Blue Pill, and can be tested on the host
+  with `cargo test`.
+*/
+#![no_std]
+
+extern crate arrayvec;
+extern crate itoa;
+extern crate embedded_hal as hal;
+
+pub mod interface;
+pub mod menu;
+pub mod entry;
+pub mod state;
+pub mod voltage;
+pub mod current;
+pub mod keypad;
+pub mod debounce;
+pub mod scanner;
+pub mod keymap;
+pub mod adc;
+pub mod control;
+pub mod filter;
+pub mod calibration;
+pub mod persistence;
+pub mod remote;
+pub mod protection;
+pub mod ramp;
+pub mod readout;
+pub mod regulation;
+pub mod units;
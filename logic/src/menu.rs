@@ -0,0 +1,582 @@
+/*!
+  Declarative menus for the keypad and the top line of the display.
+
+  A menu is a tree of static `Node`s. `Menu` keeps track of the path from the
+  root to the open node and the value being edited there. Digits pick items,
+  `Input::Confirm` submits a value and `Input::Cancel` goes back one level.
+  `Input::Confirm` on a menu sends `Command::ToggleView`.
+  Some nodes depend on the state of the supply, which is passed to every
+  update.
+*/
+
+use itoa;
+use arrayvec::{ArrayString, ArrayVec, CapacityError};
+
+use entry::{Entry, EntryUnit};
+use interface::{Command, Input, Quantity};
+use state::State;
+
+/// Characters on a line of the display
+pub const LINE_LENGTH: usize = 16;
+
+/// How many nodes can be open at once, including the root
+pub const MAX_DEPTH: usize = 4;
+
+const CONFIRM_PROMPT: &str = "Confirm 1:y 2:n";
+
+#[derive(Debug)]
+pub enum Node {
+    /// Items picked with the digits 1 to 9
+    Menu(&'static [Item]),
+    /// A value typed on the keypad
+    Number(NumberEditor),
+    /// Commands picked with the digits 1 to 9
+    Choice(&'static [Choice]),
+    /// Shows `prompt`. 1 sends `command` and 2 goes back without sending it
+    Confirm { prompt: &'static str, command: Command },
+    /// Shows `label` and whether `value` is on. 1 flips it by sending `on` or
+    /// `off` and goes back
+    Toggle { label: &'static str, value: fn(&State) -> bool, on: Command, off: Command },
+    /// Shows `label` with the range of slots. A digit from 1 to `count` sends
+    /// `command` for that slot
+    Slot { label: &'static str, count: u8, command: fn(u8) -> Command, confirm: Confirm },
+}
+
+#[derive(Debug)]
+pub struct Item {
+    /// Shown after the digit that picks the item, keep it short
+    pub label: &'static str,
+    pub node: &'static Node,
+}
+
+#[derive(Debug)]
+pub struct Choice {
+    pub label: &'static str,
+    pub command: Command,
+}
+
+/// How a number is typed and shown
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Whole or milli units with a decimal point, followed by the unit symbol
+    Decimal(&'static str),
+    /// A whole number of thousandths, followed by a suffix
+    Integer(&'static str),
+}
+
+/// When a slot node asks before sending its command
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Confirm {
+    Never,
+    /// Only ask if the output is on, since the command changes what it supplies
+    IfOutputEnabled,
+}
+
+/// Where to go once an editor has sent its command
+#[derive(Debug)]
+pub enum Then {
+    Back,
+    Open(&'static Node),
+}
+
+#[derive(Debug)]
+pub struct NumberEditor {
+    /// Shown in front of the value
+    pub label: &'static str,
+    /// Also decides the command sent with the entered value
+    pub quantity: Quantity,
+    pub format: Format,
+    /// Ask for confirmation before sending the command
+    pub confirm: bool,
+    pub then: Then,
+    /// Sent when leaving the editor with `Input::Cancel`
+    pub on_cancel: Option<Command>,
+}
+
+
+/// The state of the open node
+#[derive(Clone, Debug, PartialEq)]
+enum Edit {
+    None,
+    Decimal(Entry),
+    /// Values too large for a u16 saturate at u16::MAX, which is above every
+    /// limit so they are rejected once entered
+    Integer(u16),
+    /// The entered value, in thousandths, was outside the limits. Any key goes
+    /// back to entering it again
+    OutOfRange(u32),
+    /// Waits for 1 to send the command or 2 to go back, showing the prompt
+    Confirm(Command, ArrayString<[u8; 32]>),
+    /// Shown until the next key, which goes back
+    Message(ArrayString<[u8; 32]>),
+    /// The value of a toggle node when it was opened
+    Toggle(bool),
+}
+
+impl Edit {
+    fn new(node: &Node) -> Self {
+        match *node {
+            Node::Number(NumberEditor { format: Format::Decimal(_), .. }) => {
+                Edit::Decimal(Entry::new(EntryUnit::Whole))
+            }
+            Node::Number(NumberEditor { format: Format::Integer(_), .. }) => Edit::Integer(0),
+            _ => Edit::None,
+        }
+    }
+
+    /// Like `new`, but also shows the current value of toggle nodes
+    fn open(node: &Node, state: &State) -> Self {
+        match *node {
+            Node::Toggle { value, .. } => Edit::Toggle(value(state)),
+            _ => Edit::new(node),
+        }
+    }
+}
+
+/// What happens to the open node after an input
+enum Action {
+    Stay(Edit),
+    /// Resets the open node and opens a child
+    Open(&'static Node),
+    Back,
+}
+
+#[derive(Clone, Debug)]
+struct Frame {
+    node: &'static Node,
+    edit: Edit,
+}
+
+impl Frame {
+    fn new(node: &'static Node) -> Self {
+        Self { node, edit: Edit::new(node) }
+    }
+
+    fn update(self, input: Input, state: &State) -> (Action, Option<Command>) {
+        if input == Input::Cancel {
+            let command = match *self.node {
+                Node::Number(ref editor) => editor.on_cancel.clone(),
+                _ => None,
+            };
+            return (Action::Back, command);
+        }
+
+        match (self.node, self.edit, input) {
+            (Node::Menu(items), _, Input::Digit(digit)) => {
+                match (digit as usize).checked_sub(1).and_then(|i| items.get(i)) {
+                    Some(item) => (Action::Open(item.node), None),
+                    None => (Action::Stay(Edit::None), None),
+                }
+            }
+
+            (Node::Menu(_), _, Input::Confirm) => {
+                (Action::Stay(Edit::None), Some(Command::ToggleView))
+            }
+
+            (Node::Choice(choices), _, Input::Digit(digit)) => {
+                match (digit as usize).checked_sub(1).and_then(|i| choices.get(i)) {
+                    Some(choice) => (Action::Back, Some(choice.command.clone())),
+                    None => (Action::Stay(Edit::None), None),
+                }
+            }
+
+            (Node::Confirm { command, .. }, _, Input::Digit(1)) => {
+                (Action::Back, Some(command.clone()))
+            }
+            // Flips the value the node has now, which may have changed since it was opened
+            (Node::Toggle { value, on, off, .. }, _, Input::Digit(1)) => {
+                let command = if value(state) { off } else { on };
+                (Action::Back, Some(command.clone()))
+            }
+            (Node::Number(editor), Edit::Confirm(command, _), Input::Digit(1)) => {
+                (then_action(&editor.then), Some(command))
+            }
+            (Node::Slot { .. }, Edit::Confirm(command, _), Input::Digit(1)) => {
+                (Action::Back, Some(command))
+            }
+            (Node::Confirm { .. }, _, Input::Digit(2))
+                | (_, Edit::Confirm(..), Input::Digit(2))
+                | (_, Edit::Message(_), _) =>
+            {
+                (Action::Back, None)
+            }
+            (Node::Slot { count, command, confirm, .. }, Edit::None, Input::Digit(slot))
+                if slot >= 1 && slot <= *count =>
+            {
+                send_to_slot(command(slot), *confirm, state)
+            }
+
+            (Node::Number(_), Edit::OutOfRange(_), _) => {
+                (Action::Stay(Edit::new(self.node)), None)
+            }
+            (Node::Number(editor), Edit::Decimal(entry), Input::Confirm) => {
+                editor.submit(entry.thousandths())
+            }
+            (Node::Number(editor), Edit::Integer(val), Input::Confirm) => {
+                editor.submit(val as u32)
+            }
+            (Node::Number(_), Edit::Decimal(entry), _) => {
+                (Action::Stay(Edit::Decimal(edit_entry(entry, input))), None)
+            }
+            (Node::Number(_), Edit::Integer(val), _) => {
+                (Action::Stay(Edit::Integer(edit_integer(val, input))), None)
+            }
+
+            (_, edit, _) => (Action::Stay(edit), None),
+        }
+    }
+
+    fn push_display(&self, result: &mut ArrayString<[u8; 32]>) {
+        let mut buffer = itoa::Buffer::new();
+        match (self.node, &self.edit) {
+            (Node::Menu(items), _) => push_labels(result, items.iter().map(|item| item.label)),
+            (Node::Choice(choices), _) => {
+                push_labels(result, choices.iter().map(|choice| choice.label))
+            }
+            (Node::Confirm { prompt, .. }, _) => result.push_str(prompt),
+            (Node::Toggle { label, .. }, &Edit::Toggle(value)) => {
+                result.push_str(label);
+                result.push_str(if value { " On 1:Off" } else { " Off 1:On" });
+            }
+            (Node::Toggle { label, .. }, _) => {
+                result.push_str(label);
+                result.push_str(" 1:Flip");
+            }
+            (Node::Slot { label, count, .. }, &Edit::None) => {
+                result.push_str(label);
+                result.push_str(" 1-");
+                result.push_str(buffer.format(*count));
+            }
+            (_, &Edit::Confirm(_, ref prompt)) | (_, &Edit::Message(ref prompt)) => {
+                result.push_str(prompt)
+            }
+            (Node::Number(editor), &Edit::OutOfRange(val)) => {
+                let limits = editor.quantity.limits();
+                if val > limits.max {
+                    result.push_str("Max ");
+                    result.push_str(buffer.format(limits.max));
+                }
+                else {
+                    result.push_str("Min ");
+                    result.push_str(buffer.format(limits.min));
+                }
+                result.push_str(editor.quantity.unit());
+            }
+            (Node::Number(editor), &Edit::Decimal(entry)) => {
+                result.push_str(editor.label);
+                if let Format::Decimal(unit) = editor.format {
+                    entry.push_display(result, unit);
+                }
+            }
+            (Node::Number(editor), &Edit::Integer(val)) => {
+                result.push_str(editor.label);
+                result.push_str(buffer.format(val));
+                if let Format::Integer(suffix) = editor.format {
+                    result.push_str(suffix);
+                }
+            }
+            (Node::Number(_), _) | (Node::Slot { .. }, _) => {}
+        }
+    }
+}
+
+impl NumberEditor {
+    /// Checks an entered value, `val` is in thousandths of the unit
+    fn submit(&self, val: u32) -> (Action, Option<Command>) {
+        match self.quantity.check(val) {
+            Some(command) => {
+                if self.confirm {
+                    let prompt = ArrayString::from(CONFIRM_PROMPT).unwrap();
+                    (Action::Stay(Edit::Confirm(command, prompt)), None)
+                }
+                else {
+                    (then_action(&self.then), Some(command))
+                }
+            }
+            None => (Action::Stay(Edit::OutOfRange(val)), None),
+        }
+    }
+}
+
+/**
+  Lists labels after the digits that pick them. Lists with many labels leave
+  out the colons to fit on a line
+*/
+fn push_labels<'a, I>(result: &mut ArrayString<[u8; 32]>, labels: I)
+where I: Iterator<Item = &'a str> + Clone
+{
+    let length = labels.clone().map(|label| label.len() + 3).sum::<usize>().saturating_sub(1);
+    let separator = if length <= LINE_LENGTH { ":" } else { "" };
+    let mut buffer = itoa::Buffer::new();
+    for (i, label) in labels.enumerate() {
+        if i != 0 {
+            result.push_str(" ");
+        }
+        result.push_str(buffer.format(i + 1));
+        result.push_str(separator);
+        result.push_str(label);
+    }
+}
+
+/**
+  Sends a command picked on a slot node. Commands with a preview, like
+  recalling a preset, show it while asking for confirmation or once the
+  command is sent. Commands that can't be sent show why.
+*/
+fn send_to_slot(command: Command, confirm: Confirm, state: &State) -> (Action, Option<Command>) {
+    let confirm = confirm == Confirm::IfOutputEnabled && state.output_enabled();
+    match state.preview(&command) {
+        Err(reason) => (Action::Stay(Edit::Message(ArrayString::from(reason).unwrap())), None),
+        Ok(None) if confirm => {
+            let prompt = ArrayString::from(CONFIRM_PROMPT).unwrap();
+            (Action::Stay(Edit::Confirm(command, prompt)), None)
+        }
+        Ok(None) => (Action::Back, Some(command)),
+        Ok(Some(mut preview)) => {
+            if confirm {
+                preview.push_str(" 1:y");
+                (Action::Stay(Edit::Confirm(command, preview)), None)
+            }
+            else {
+                (Action::Stay(Edit::Message(preview)), Some(command))
+            }
+        }
+    }
+}
+
+fn then_action(then: &Then) -> Action {
+    match *then {
+        Then::Back => Action::Back,
+        Then::Open(node) => Action::Open(node),
+    }
+}
+
+
+/// The open path through a menu tree
+#[derive(Clone, Debug)]
+pub struct Menu {
+    stack: ArrayVec<[Frame; MAX_DEPTH]>,
+}
+
+impl Menu {
+    pub fn new(root: &'static Node) -> Self {
+        let mut stack = ArrayVec::new();
+        stack.push(Frame::new(root));
+        Self { stack }
+    }
+
+    /// How many nodes are open below the root
+    pub fn depth(&self) -> usize {
+        self.stack.len() - 1
+    }
+
+    pub fn update(mut self, input: Input, state: &State) -> (Self, Option<Command>) {
+        let top = self.stack.pop().expect("The root of a menu is never closed");
+        let node = top.node;
+        let (action, command) = top.update(input, state);
+
+        match action {
+            Action::Stay(edit) => self.stack.push(Frame { node, edit }),
+            Action::Open(child) => {
+                self.stack.push(Frame::new(node));
+                // Nodes nested deeper than MAX_DEPTH can't be opened
+                let _ = self.stack.try_push(Frame { node: child, edit: Edit::open(child, state) });
+            }
+            Action::Back => {
+                if self.stack.is_empty() {
+                    self.stack.push(Frame::new(node));
+                }
+            }
+        }
+        (self, command)
+    }
+
+    pub fn get_display(&self) -> Result<ArrayString<[u8; 32]>, CapacityError<&str>> {
+        let mut result = ArrayString::new();
+        self.stack.last()
+            .expect("The root of a menu is never closed")
+            .push_display(&mut result);
+        Ok(result)
+    }
+}
+
+
+
+/**
+  Applies an editing input to an integer entry. Values too large for a u16
+  saturate at u16::MAX
+*/
+fn edit_integer(val: u16, input: Input) -> u16 {
+    match input {
+        Input::Digit(digit) => val.saturating_mul(10).saturating_add(digit as u16),
+        Input::Backspace => val / 10,
+        Input::Clear => 0,
+        _ => val
+    }
+}
+
+fn edit_entry(entry: Entry, input: Input) -> Entry {
+    match input {
+        Input::Digit(digit) => entry.push_digit(digit),
+        Input::DecimalPoint => entry.push_decimal_point(),
+        Input::Backspace => entry.pop(),
+        Input::Clear => entry.clear(),
+        _ => entry
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static LEVEL: Node = Node::Number(NumberEditor {
+        label: "Level ",
+        quantity: Quantity::CalibrationDuty,
+        format: Format::Integer("/1000"),
+        confirm: false,
+        then: Then::Back,
+        on_cancel: None,
+    });
+
+    static RESET: Node = Node::Confirm { prompt: "Reset? 1:y 2:n", command: Command::CalibrationDone };
+
+    static OUTPUT: Node = Node::Choice(&[
+        Choice { label: "On", command: Command::OutputOn },
+        Choice { label: "Off", command: Command::OutputOff },
+    ]);
+
+    static SUBMENU: Node = Node::Menu(&[
+        Item { label: "Lvl", node: &LEVEL },
+        Item { label: "Rst", node: &RESET },
+    ]);
+
+    static ROOT: Node = Node::Menu(&[
+        Item { label: "Out", node: &OUTPUT },
+        Item { label: "More", node: &SUBMENU },
+    ]);
+
+    fn run(menu: Menu, seq: &[Input]) -> (Menu, Option<Command>) {
+        let state = State::new(false);
+        seq.iter().fold((menu, None), |(menu, _), &input| menu.update(input, &state))
+    }
+
+    fn display(menu: &Menu) -> ArrayString<[u8; 32]> {
+        menu.get_display().unwrap()
+    }
+
+    use interface::Input::{Digit, Confirm, Cancel};
+
+    #[test]
+    fn menus_list_items() {
+        let menu = Menu::new(&ROOT);
+        assert_eq!(display(&menu).as_str(), "1:Out 2:More");
+        let (menu, _) = run(menu, &[Digit(2)]);
+        assert_eq!(display(&menu).as_str(), "1:Lvl 2:Rst");
+        assert_eq!(menu.depth(), 1);
+    }
+
+    #[test]
+    fn unknown_items_are_ignored() {
+        let (menu, command) = run(Menu::new(&ROOT), &[Digit(0), Digit(3)]);
+        assert_eq!(menu.depth(), 0);
+        assert_eq!(command, None);
+    }
+
+    #[test]
+    fn confirm_on_a_menu_toggles_the_view() {
+        let (menu, command) = run(Menu::new(&ROOT), &[Digit(2), Confirm]);
+        assert_eq!(display(&menu).as_str(), "1:Lvl 2:Rst");
+        assert_eq!(command, Some(Command::ToggleView));
+    }
+
+    #[test]
+    fn choice() {
+        let (menu, command) = run(Menu::new(&ROOT), &[Digit(1)]);
+        assert_eq!(display(&menu).as_str(), "1:On 2:Off");
+        assert_eq!(run(menu.clone(), &[Digit(1)]).1, Some(Command::OutputOn));
+        let (menu, command_off) = run(menu, &[Digit(2)]);
+        assert_eq!(command, None);
+        assert_eq!(command_off, Some(Command::OutputOff));
+        assert_eq!(menu.depth(), 0);
+    }
+
+    #[test]
+    fn back_navigation() {
+        let (menu, _) = run(Menu::new(&ROOT), &[Digit(2), Digit(1), Digit(5)]);
+        assert_eq!(display(&menu).as_str(), "Level 5/1000");
+        assert_eq!(menu.depth(), 2);
+        let (menu, _) = run(menu, &[Cancel]);
+        assert_eq!(display(&menu).as_str(), "1:Lvl 2:Rst");
+        let (menu, _) = run(menu, &[Cancel]);
+        assert_eq!(menu.depth(), 0);
+        let (menu, _) = run(menu, &[Cancel]);
+        assert_eq!(menu.depth(), 0);
+    }
+
+    #[test]
+    fn editor_goes_back_after_submitting() {
+        let (menu, command) = run(Menu::new(&ROOT), &[Digit(2), Digit(1), Digit(5), Confirm]);
+        assert_eq!(command, Some(Command::CalibrationDuty(0.005)));
+        assert_eq!(display(&menu).as_str(), "1:Lvl 2:Rst");
+        // The editor starts over when it is opened again
+        let (menu, _) = run(menu, &[Digit(1)]);
+        assert_eq!(display(&menu).as_str(), "Level 0/1000");
+    }
+
+    #[test]
+    fn confirmation_node() {
+        let (menu, command) = run(Menu::new(&ROOT), &[Digit(2), Digit(2)]);
+        assert_eq!(display(&menu).as_str(), "Reset? 1:y 2:n");
+        assert_eq!(command, None);
+        assert_eq!(run(menu.clone(), &[Digit(1)]).1, Some(Command::CalibrationDone));
+        let (menu, command) = run(menu, &[Digit(2)]);
+        assert_eq!(command, None);
+        assert_eq!(display(&menu).as_str(), "1:Lvl 2:Rst");
+    }
+
+    #[test]
+    fn toggle() {
+        static SWITCH: Node = Node::Toggle {
+            label: "Out",
+            value: State::software_enabled,
+            on: Command::OutputOn,
+            off: Command::OutputOff,
+        };
+        static SWITCHES: Node = Node::Menu(&[Item { label: "Out", node: &SWITCH }]);
+
+        let mut state = State::new(false);
+        state.set_software_enabled(false);
+        let (menu, command) = Menu::new(&SWITCHES).update(Digit(1), &state);
+        assert_eq!(command, None);
+        assert_eq!(display(&menu).as_str(), "Out Off 1:On");
+        let (menu, command) = menu.update(Digit(1), &state);
+        assert_eq!(command, Some(Command::OutputOn));
+        assert_eq!(menu.depth(), 0);
+
+        state.set_software_enabled(true);
+        let (menu, _) = menu.update(Digit(1), &state);
+        assert_eq!(display(&menu).as_str(), "Out On 1:Off");
+        // Other digits do nothing
+        let (menu, command) = menu.update(Digit(2), &state);
+        assert_eq!(command, None);
+        let (_, command) = menu.update(Digit(1), &state);
+        assert_eq!(command, Some(Command::OutputOff));
+    }
+
+    #[test]
+    fn out_of_range() {
+        let (menu, _) = run(Menu::new(&SUBMENU), &[Digit(1), Digit(2), Digit(0), Digit(0), Digit(0), Confirm]);
+        assert_eq!(display(&menu).as_str(), "Max 1000/1000");
+        let (menu, command) = run(menu, &[Digit(7)]);
+        assert_eq!(command, None);
+        assert_eq!(display(&menu).as_str(), "Level 0/1000");
+    }
+
+    #[test]
+    fn deep_nodes_are_not_opened() {
+        static DEEP: Node = Node::Menu(&[Item { label: "D", node: &DEEP }]);
+        let (menu, _) = run(Menu::new(&DEEP), &[Digit(1); MAX_DEPTH + 2]);
+        assert_eq!(menu.depth(), MAX_DEPTH - 1);
+    }
+}
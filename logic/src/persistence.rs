@@ -0,0 +1,857 @@
+/*!
+  Storage of settings across power cycles.
+
+  Settings are written as fixed size records one after another through the
+  pages of the storage, wrapping around at the end, so every page is erased
+  equally often. Each record carries a sequence number and a CRC. On load the
+  valid record with the highest sequence number wins, which means that a
+  write interrupted by a power loss leaves the previous settings in place.
+
+  Record layout, all values little endian:
+
+  | Offset | Size | Content                                  |
+  |--------|------|------------------------------------------|
+  | 0      | 2    | `MAGIC`                                  |
+  | 2      | 1    | `VERSION`                                |
+  | 3      | 1    | Number of calibration points             |
+  | 4      | 4    | Sequence number                          |
+  | 8      | 4    | Voltage setpoint in mV                   |
+  | 12     | 4    | Current limit in mA                      |
+  | 16     | 64   | Calibration points, duty and voltage     |
+  | 80     | 1    | Presets in use, bit n for slot n + 1     |
+  | 81     | 3    | Reserved, written as 0xff                |
+  | 84     | 32   | Presets, voltage and current limit       |
+  | 116    | 4    | Over-voltage threshold in mV             |
+  | 120    | 4    | Over-current trip delay in ms            |
+  | 124    | 4    | CRC-32 of bytes 0 to 123                 |
+
+  Older versions leave out the fields added after them, those bytes are
+  reserved and the fields get their defaults:
+
+  - Version 1 has no presets, bytes 80 to 123 are reserved.
+  - Version 2 has no over-voltage threshold, bytes 116 to 123 are reserved.
+  - Version 3 has no over-current trip delay, bytes 120 to 123 are reserved.
+
+  Before version 5, setpoints and thresholds were stored as `f32` in V and A.
+  Those are rounded to whole mV and mA on load.
+
+  A record only has to pass the CRC to be loaded, so the loaded settings are
+  clamped to the limits that apply when they are entered. A record written by
+  firmware with wider limits then can't restore a setpoint that the current
+  firmware would refuse.
+
+  Every save erases a page once the records have gone around it, and an erase
+  stalls the CPU for tens of ms. Settings changed by a script over the remote
+  port can change many times a second, so `DelayedSave` holds those back
+  until they have been left alone for `SAVE_DELAY`.
+*/
+
+use calibration::{Calibration, CalibrationPoint, MAX_POINTS};
+use interface::Quantity;
+use state::{Preset, PRESET_SLOTS};
+use protection::{DEFAULT_OVER_CURRENT_DELAY, DEFAULT_OVER_VOLTAGE};
+use remote::{OVER_CURRENT_DELAY_LIMITS, OVER_VOLTAGE_LIMITS};
+use units::{MilliAmps, MilliVolts};
+use voltage::MIN_VOLTAGE;
+
+pub const MAGIC: u16 = 0x5053;
+pub const VERSION: u8 = 5;
+pub const RECORD_SIZE: usize = 128;
+/// How long settings have to stay the same before `DelayedSave` saves them, in ms
+pub const SAVE_DELAY: u32 = 5_000;
+
+const ERASED: u8 = 0xff;
+const CALIBRATION_OFFSET: usize = 16;
+const PRESET_MASK_OFFSET: usize = 80;
+const PRESETS_OFFSET: usize = 84;
+const OVER_VOLTAGE_OFFSET: usize = 116;
+const OVER_CURRENT_DELAY_OFFSET: usize = 120;
+const CRC_OFFSET: usize = RECORD_SIZE - 4;
+
+/**
+  Flash-like storage. Erasing sets every byte in a page to 0xff and writes
+  are only done to erased bytes.
+*/
+pub trait Storage {
+    type Error;
+
+    /// Size of an erasable page in bytes
+    fn page_size(&self) -> usize;
+    fn page_count(&self) -> usize;
+    /// Reads `buffer.len()` bytes from `address`, relative to the start of the storage
+    fn read(&mut self, address: usize, buffer: &mut [u8]) -> Result<(), Self::Error>;
+    fn write(&mut self, address: usize, data: &[u8]) -> Result<(), Self::Error>;
+    fn erase_page(&mut self, page: usize) -> Result<(), Self::Error>;
+}
+
+/// The settings which are kept across power cycles
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub voltage: MilliVolts,
+    pub over_voltage: MilliVolts,
+    pub current_limit: MilliAmps,
+    /// In ms
+    pub over_current_delay: u32,
+    pub calibration: Calibration,
+    pub presets: [Option<Preset>; PRESET_SLOTS],
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            voltage: MIN_VOLTAGE,
+            over_voltage: DEFAULT_OVER_VOLTAGE,
+            current_limit: MilliAmps(0),
+            over_current_delay: DEFAULT_OVER_CURRENT_DELAY,
+            calibration: Calibration::default(),
+            presets: [None; PRESET_SLOTS],
+        }
+    }
+}
+
+impl Settings {
+    /**
+      Clamps the setpoints, presets and protection settings to the limits
+      they are entered with
+    */
+    pub fn clamp_to_limits(&mut self) {
+        let voltage_limits = Quantity::Voltage.limits();
+        let current_limits = Quantity::Current.limits();
+        let clamp_voltage = |voltage: MilliVolts| MilliVolts(voltage_limits.clamp(voltage.0));
+        let clamp_current = |current: MilliAmps| MilliAmps(current_limits.clamp(current.0));
+
+        self.voltage = clamp_voltage(self.voltage);
+        self.current_limit = clamp_current(self.current_limit);
+        self.over_voltage = MilliVolts(OVER_VOLTAGE_LIMITS.clamp(self.over_voltage.0));
+        self.over_current_delay = OVER_CURRENT_DELAY_LIMITS.clamp(self.over_current_delay);
+        for preset in self.presets.iter_mut().flatten() {
+            preset.voltage = clamp_voltage(preset.voltage);
+            preset.current_limit = clamp_current(preset.current_limit);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodeError {
+    /// The slot has never been written
+    Blank,
+    BadMagic,
+    UnknownVersion(u8),
+    BadCrc,
+    BadCalibration,
+    /// A setpoint is negative or not a number
+    BadSetpoint,
+}
+
+pub fn encode(settings: &Settings, sequence: u32) -> [u8; RECORD_SIZE] {
+    let mut record = [ERASED; RECORD_SIZE];
+    let points = settings.calibration.points();
+
+    put_u16(&mut record, 0, MAGIC);
+    record[2] = VERSION;
+    record[3] = points.len() as u8;
+    put_u32(&mut record, 4, sequence);
+    put_u32(&mut record, 8, settings.voltage.0);
+    put_u32(&mut record, 12, settings.current_limit.0);
+    for (i, point) in points.iter().enumerate() {
+        let offset = CALIBRATION_OFFSET + i * 8;
+        put_f32(&mut record, offset, point.duty);
+        put_f32(&mut record, offset + 4, point.voltage);
+    }
+    let mut preset_mask = 0;
+    for (i, preset) in settings.presets.iter().enumerate() {
+        if let Some(preset) = preset {
+            preset_mask |= 1 << i;
+            let offset = PRESETS_OFFSET + i * 8;
+            put_u32(&mut record, offset, preset.voltage.0);
+            put_u32(&mut record, offset + 4, preset.current_limit.0);
+        }
+    }
+    record[PRESET_MASK_OFFSET] = preset_mask;
+    put_u32(&mut record, OVER_VOLTAGE_OFFSET, settings.over_voltage.0);
+    put_u32(&mut record, OVER_CURRENT_DELAY_OFFSET, settings.over_current_delay);
+    let crc = crc32(&record[..CRC_OFFSET]);
+    put_u32(&mut record, CRC_OFFSET, crc);
+
+    record
+}
+
+/// Returns the sequence number and settings stored in a record
+pub fn decode(record: &[u8; RECORD_SIZE]) -> Result<(u32, Settings), DecodeError> {
+    if record.iter().all(|&byte| byte == ERASED) {
+        return Err(DecodeError::Blank);
+    }
+    if get_u16(record, 0) != MAGIC {
+        return Err(DecodeError::BadMagic);
+    }
+    if crc32(&record[..CRC_OFFSET]) != get_u32(record, CRC_OFFSET) {
+        return Err(DecodeError::BadCrc);
+    }
+    let version = record[2];
+    if version == 0 || version > VERSION {
+        return Err(DecodeError::UnknownVersion(version));
+    }
+
+    let point_count = record[3] as usize;
+    if point_count > MAX_POINTS {
+        return Err(DecodeError::BadCalibration);
+    }
+    let mut points = [CalibrationPoint { duty: 0., voltage: 0. }; MAX_POINTS];
+    for (i, point) in points.iter_mut().take(point_count).enumerate() {
+        let offset = CALIBRATION_OFFSET + i * 8;
+        point.duty = get_f32(record, offset);
+        point.voltage = get_f32(record, offset + 4);
+    }
+    let calibration = Calibration::fit(&points[..point_count])
+        .map_err(|_| DecodeError::BadCalibration)?;
+
+    let mut presets = [None; PRESET_SLOTS];
+    if version >= 2 {
+        for (i, preset) in presets.iter_mut().enumerate() {
+            if record[PRESET_MASK_OFFSET] & 1 << i != 0 {
+                let offset = PRESETS_OFFSET + i * 8;
+                *preset = Some(Preset {
+                    voltage: get_millivolts(record, version, offset)?,
+                    current_limit: get_milliamps(record, version, offset + 4)?,
+                });
+            }
+        }
+    }
+
+    let over_voltage = if version >= 3 {
+        get_millivolts(record, version, OVER_VOLTAGE_OFFSET)?
+    }
+    else {
+        DEFAULT_OVER_VOLTAGE
+    };
+
+    let over_current_delay = if version >= 4 {
+        get_u32(record, OVER_CURRENT_DELAY_OFFSET)
+    }
+    else {
+        DEFAULT_OVER_CURRENT_DELAY
+    };
+
+    let mut settings = Settings {
+        voltage: get_millivolts(record, version, 8)?,
+        over_voltage,
+        current_limit: get_milliamps(record, version, 12)?,
+        over_current_delay,
+        calibration,
+        presets,
+    };
+    settings.clamp_to_limits();
+    Ok((get_u32(record, 4), settings))
+}
+
+
+/**
+  Finds the latest record in `storage` and appends new records after it
+*/
+pub struct Persistence<S: Storage> {
+    storage: S,
+    latest: Option<(usize, u32)>,
+}
+
+impl<S: Storage> Persistence<S> {
+    pub fn new(storage: S) -> Self {
+        Self {
+            storage,
+            latest: None,
+        }
+    }
+
+    /**
+      Scans the storage for the newest valid record. Returns `None` if no
+      valid record is found, for example on the first boot.
+    */
+    pub fn load(&mut self) -> Result<Option<Settings>, S::Error> {
+        let mut newest = None;
+        for slot in 0..self.slot_count() {
+            if let Ok((sequence, settings)) = decode(&self.read_slot(slot)?) {
+                let is_newer = match newest {
+                    Some((_, newest_sequence, _)) => sequence > newest_sequence,
+                    None => true,
+                };
+                if is_newer {
+                    newest = Some((slot, sequence, settings));
+                }
+            }
+        }
+
+        self.latest = newest.as_ref().map(|&(slot, sequence, _)| (slot, sequence));
+        Ok(newest.map(|(_, _, settings)| settings))
+    }
+
+    /**
+      Writes `settings` to the slot after the latest record. Pages are erased
+      when the first slot in them is reached. A slot which isn't blank, for
+      example because of an interrupted write, is skipped by moving on to the
+      next page.
+
+      `load` has to be called first to find where the latest record is.
+    */
+    pub fn save(&mut self, settings: &Settings) -> Result<(), S::Error> {
+        let (mut slot, sequence) = match self.latest {
+            Some((slot, sequence)) => ((slot + 1) % self.slot_count(), sequence.wrapping_add(1)),
+            None => (0, 0),
+        };
+
+        let slots_per_page = self.slots_per_page();
+        if slot % slots_per_page == 0 {
+            self.storage.erase_page(slot / slots_per_page)?;
+        }
+        else if self.read_slot(slot)?.iter().any(|&byte| byte != ERASED) {
+            slot = (slot / slots_per_page + 1) % self.page_count() * slots_per_page;
+            self.storage.erase_page(slot / slots_per_page)?;
+        }
+
+        let record = encode(settings, sequence);
+        self.storage.write(slot * RECORD_SIZE, &record)?;
+        self.latest = Some((slot, sequence));
+        Ok(())
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    fn read_slot(&mut self, slot: usize) -> Result<[u8; RECORD_SIZE], S::Error> {
+        let mut record = [0; RECORD_SIZE];
+        self.storage.read(slot * RECORD_SIZE, &mut record)?;
+        Ok(record)
+    }
+
+    fn slots_per_page(&self) -> usize {
+        self.storage.page_size() / RECORD_SIZE
+    }
+
+    fn page_count(&self) -> usize {
+        self.storage.page_count()
+    }
+
+    fn slot_count(&self) -> usize {
+        self.slots_per_page() * self.page_count()
+    }
+}
+
+
+/**
+  Decides when changed settings are saved. Settings are only saved once they
+  have been the same for `SAVE_DELAY`, so a sweep through many values ends up
+  as one save at the end.
+*/
+pub struct DelayedSave {
+    saved: Settings,
+    /// Unsaved settings and when they were first seen, in ms
+    pending: Option<(Settings, u32)>,
+}
+
+impl DelayedSave {
+    /// `saved` are the settings which are already in the storage
+    pub fn new(saved: Settings) -> Self {
+        Self {
+            saved,
+            pending: None,
+        }
+    }
+
+    /**
+      Looks at the current settings at `now` ms. Returns the settings to save
+      once they have differed from the saved ones for long enough.
+    */
+    pub fn poll(&mut self, settings: &Settings, now: u32) -> Option<Settings> {
+        if self.is_saved(settings) {
+            self.pending = None;
+            return None;
+        }
+        match self.pending {
+            Some((ref pending, since)) if pending == settings => {
+                if now.wrapping_sub(since) < SAVE_DELAY {
+                    return None;
+                }
+            }
+            _ => {
+                self.pending = Some((settings.clone(), now));
+                return None;
+            }
+        }
+        self.saved(settings.clone());
+        Some(self.saved.clone())
+    }
+
+    /// Returns true if `settings` are the last saved ones
+    pub fn is_saved(&self, settings: &Settings) -> bool {
+        *settings == self.saved
+    }
+
+    /// Records that `settings` were saved without waiting
+    pub fn saved(&mut self, settings: Settings) {
+        self.saved = settings;
+        self.pending = None;
+    }
+}
+
+
+/// CRC-32 (IEEE 802.3)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn put_u16(buffer: &mut [u8], offset: usize, value: u16) {
+    buffer[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buffer: &mut [u8], offset: usize, value: u32) {
+    buffer[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_f32(buffer: &mut [u8], offset: usize, value: f32) {
+    put_u32(buffer, offset, value.to_bits());
+}
+
+fn get_u16(buffer: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buffer[offset], buffer[offset + 1]])
+}
+
+fn get_u32(buffer: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buffer[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn get_f32(buffer: &[u8], offset: usize) -> f32 {
+    f32::from_bits(get_u32(buffer, offset))
+}
+
+/// Reads a voltage in the format of the record `version`
+fn get_millivolts(buffer: &[u8], version: u8, offset: usize) -> Result<MilliVolts, DecodeError> {
+    if version >= 5 {
+        Ok(MilliVolts(get_u32(buffer, offset)))
+    }
+    else {
+        MilliVolts::from_volts(get_f32(buffer, offset)).ok_or(DecodeError::BadSetpoint)
+    }
+}
+
+/// Reads a current in the format of the record `version`
+fn get_milliamps(buffer: &[u8], version: u8, offset: usize) -> Result<MilliAmps, DecodeError> {
+    if version >= 5 {
+        Ok(MilliAmps(get_u32(buffer, offset)))
+    }
+    else {
+        MilliAmps::from_amps(get_f32(buffer, offset)).ok_or(DecodeError::BadSetpoint)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use interface::Quantity;
+    use remote::OVER_VOLTAGE_LIMITS;
+
+    const PAGE_SIZE: usize = 512;
+    const PAGE_COUNT: usize = 2;
+
+    #[derive(Debug, PartialEq)]
+    enum FakeError {
+        OutOfBounds,
+        NotErased,
+    }
+
+    /// In memory storage with the same write restrictions as flash
+    struct FakeFlash {
+        data: [u8; PAGE_SIZE * PAGE_COUNT],
+        erase_counts: [u32; PAGE_COUNT],
+    }
+
+    impl FakeFlash {
+        fn new() -> Self {
+            Self {
+                data: [ERASED; PAGE_SIZE * PAGE_COUNT],
+                erase_counts: [0; PAGE_COUNT],
+            }
+        }
+    }
+
+    impl Storage for FakeFlash {
+        type Error = FakeError;
+
+        fn page_size(&self) -> usize {
+            PAGE_SIZE
+        }
+        fn page_count(&self) -> usize {
+            PAGE_COUNT
+        }
+        fn read(&mut self, address: usize, buffer: &mut [u8]) -> Result<(), FakeError> {
+            let source = self.data.get(address..address + buffer.len())
+                .ok_or(FakeError::OutOfBounds)?;
+            buffer.copy_from_slice(source);
+            Ok(())
+        }
+        fn write(&mut self, address: usize, data: &[u8]) -> Result<(), FakeError> {
+            let target = self.data.get_mut(address..address + data.len())
+                .ok_or(FakeError::OutOfBounds)?;
+            if target.iter().any(|&byte| byte != ERASED) {
+                return Err(FakeError::NotErased);
+            }
+            target.copy_from_slice(data);
+            Ok(())
+        }
+        fn erase_page(&mut self, page: usize) -> Result<(), FakeError> {
+            let start = page * PAGE_SIZE;
+            for byte in &mut self.data[start..start + PAGE_SIZE] {
+                *byte = ERASED;
+            }
+            self.erase_counts[page] += 1;
+            Ok(())
+        }
+    }
+
+    fn settings(millivolts: u32) -> Settings {
+        Settings {
+            voltage: MilliVolts(millivolts),
+            over_voltage: MilliVolts(15_000),
+            current_limit: MilliAmps(500),
+            over_current_delay: 25,
+            calibration: Calibration::fit(&[
+                CalibrationPoint { duty: 0.1, voltage: 2. },
+                CalibrationPoint { duty: 0.5, voltage: 9.5 },
+                CalibrationPoint { duty: 0.9, voltage: 19. },
+            ]).unwrap(),
+            presets: [
+                Some(Preset { voltage: MilliVolts(3300), current_limit: MilliAmps(100) }),
+                None,
+                Some(Preset { voltage: MilliVolts(12_000), current_limit: MilliAmps(2000) }),
+                None,
+            ],
+        }
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let record = encode(&settings(12_500), 42);
+        assert_eq!(decode(&record), Ok((42, settings(12_500))));
+    }
+
+    #[test]
+    fn corrupted_record_is_rejected() {
+        let mut record = encode(&settings(12_500), 42);
+        record[9] ^= 0x10;
+        assert_eq!(decode(&record), Err(DecodeError::BadCrc));
+    }
+
+    #[test]
+    fn unknown_version_is_rejected() {
+        let mut record = encode(&settings(12_500), 42);
+        record[2] = VERSION + 1;
+        let crc = crc32(&record[..CRC_OFFSET]);
+        put_u32(&mut record, CRC_OFFSET, crc);
+        assert_eq!(decode(&record), Err(DecodeError::UnknownVersion(VERSION + 1)));
+    }
+
+    /// Encodes `settings` the way firmware writing record `version` 1 to 4 did
+    fn encode_old(settings: &Settings, sequence: u32, version: u8) -> [u8; RECORD_SIZE] {
+        let mut record = encode(settings, sequence);
+        record[2] = version;
+        put_f32(&mut record, 8, settings.voltage.volts());
+        put_f32(&mut record, 12, settings.current_limit.amps());
+        for (i, preset) in settings.presets.iter().enumerate() {
+            if let Some(preset) = preset {
+                let offset = PRESETS_OFFSET + i * 8;
+                put_f32(&mut record, offset, preset.voltage.volts());
+                put_f32(&mut record, offset + 4, preset.current_limit.amps());
+            }
+        }
+        put_f32(&mut record, OVER_VOLTAGE_OFFSET, settings.over_voltage.volts());
+
+        // The fields added in later versions are reserved
+        let reserved = match version {
+            1 => PRESET_MASK_OFFSET,
+            2 => OVER_VOLTAGE_OFFSET,
+            3 => OVER_CURRENT_DELAY_OFFSET,
+            _ => CRC_OFFSET,
+        };
+        for byte in &mut record[reserved..CRC_OFFSET] {
+            *byte = ERASED;
+        }
+        let crc = crc32(&record[..CRC_OFFSET]);
+        put_u32(&mut record, CRC_OFFSET, crc);
+        record
+    }
+
+    #[test]
+    fn setpoints_are_stored_in_milli_units() {
+        let record = encode(&settings(12_345), 42);
+        assert_eq!(get_u32(&record, 8), 12_345);
+        assert_eq!(get_u32(&record, 12), 500);
+        assert_eq!(get_u32(&record, PRESETS_OFFSET), 3300);
+        assert_eq!(get_u32(&record, OVER_VOLTAGE_OFFSET), 15_000);
+    }
+
+    #[test]
+    fn version_1_records_have_no_presets() {
+        let record = encode_old(&settings(12_500), 42, 1);
+
+        let expected = Settings {
+            presets: [None; PRESET_SLOTS],
+            over_voltage: DEFAULT_OVER_VOLTAGE,
+            over_current_delay: DEFAULT_OVER_CURRENT_DELAY,
+            ..settings(12_500)
+        };
+        assert_eq!(decode(&record), Ok((42, expected)));
+    }
+
+    #[test]
+    fn version_2_records_have_no_over_voltage_threshold() {
+        let record = encode_old(&settings(12_500), 42, 2);
+
+        let expected = Settings {
+            over_voltage: DEFAULT_OVER_VOLTAGE,
+            over_current_delay: DEFAULT_OVER_CURRENT_DELAY,
+            ..settings(12_500)
+        };
+        assert_eq!(decode(&record), Ok((42, expected)));
+    }
+
+    #[test]
+    fn version_3_records_have_no_over_current_delay() {
+        let record = encode_old(&settings(12_500), 42, 3);
+
+        let expected = Settings { over_current_delay: DEFAULT_OVER_CURRENT_DELAY, ..settings(12_500) };
+        assert_eq!(decode(&record), Ok((42, expected)));
+    }
+
+    #[test]
+    fn every_preset_slot_round_trips() {
+        let preset = Some(Preset { voltage: MilliVolts(5000), current_limit: MilliAmps(500) });
+        let full = Settings { presets: [preset; PRESET_SLOTS], ..settings(12_500) };
+        assert_eq!(decode(&encode(&full, 42)), Ok((42, full)));
+    }
+
+    /// Every setpoint and threshold that can be entered, in the settings of `settings(5_000)`
+    fn every_entered_setpoint() -> impl Iterator<Item = Settings> {
+        let voltages = Quantity::Voltage.limits();
+        let over_voltages = OVER_VOLTAGE_LIMITS;
+        let currents = Quantity::Current.limits();
+        (voltages.min..=voltages.max)
+            .map(settings)
+            .chain((over_voltages.min..=over_voltages.max).map(|millivolts| Settings {
+                over_voltage: MilliVolts(millivolts),
+                ..settings(5_000)
+            }))
+            .chain((currents.min..=currents.max).map(|milliamps| Settings {
+                current_limit: MilliAmps(milliamps),
+                ..settings(5_000)
+            }))
+    }
+
+    #[test]
+    fn every_setpoint_round_trips() {
+        for stored in every_entered_setpoint() {
+            assert_eq!(decode(&encode(&stored, 1)), Ok((1, stored)));
+        }
+    }
+
+    #[test]
+    fn every_setpoint_in_version_4_records_is_rounded_back() {
+        for stored in every_entered_setpoint() {
+            assert_eq!(decode(&encode_old(&stored, 1, 4)), Ok((1, stored)));
+        }
+    }
+
+    #[test]
+    fn version_4_records_store_setpoints_as_f32() {
+        let record = encode_old(&settings(5_000), 1, 4);
+        assert_eq!(get_f32(&record, 8), 5.);
+        assert_eq!(decode(&record), Ok((1, settings(5_000))));
+    }
+
+    #[test]
+    fn out_of_range_setpoints_are_clamped() {
+        let wide = Settings {
+            voltage: MilliVolts(0),
+            over_voltage: MilliVolts(60_000),
+            current_limit: MilliAmps(10_000),
+            over_current_delay: 100_000,
+            presets: [
+                Some(Preset { voltage: MilliVolts(30_000), current_limit: MilliAmps(5_000) }),
+                None,
+                None,
+                None,
+            ],
+            ..settings(5_000)
+        };
+        let voltage_limits = Quantity::Voltage.limits();
+        let current_limits = Quantity::Current.limits();
+        let clamped = Settings {
+            voltage: MilliVolts(voltage_limits.min),
+            over_voltage: MilliVolts(OVER_VOLTAGE_LIMITS.max),
+            current_limit: MilliAmps(current_limits.max),
+            over_current_delay: OVER_CURRENT_DELAY_LIMITS.max,
+            presets: [
+                Some(Preset {
+                    voltage: MilliVolts(voltage_limits.max),
+                    current_limit: MilliAmps(current_limits.max),
+                }),
+                None,
+                None,
+                None,
+            ],
+            ..settings(5_000)
+        };
+        assert_eq!(decode(&encode(&wide, 1)), Ok((1, clamped.clone())));
+        assert_eq!(decode(&encode_old(&wide, 1, 4)), Ok((1, clamped)));
+    }
+
+    #[test]
+    fn negative_setpoints_in_version_3_records_are_rejected() {
+        let mut record = encode_old(&settings(5_000), 1, 3);
+        put_f32(&mut record, 12, -1.);
+        let crc = crc32(&record[..CRC_OFFSET]);
+        put_u32(&mut record, CRC_OFFSET, crc);
+        assert_eq!(decode(&record), Err(DecodeError::BadSetpoint));
+    }
+
+    #[test]
+    fn blank_storage_loads_nothing() {
+        let mut persistence = Persistence::new(FakeFlash::new());
+        assert_eq!(persistence.load(), Ok(None));
+    }
+
+    #[test]
+    fn latest_save_is_loaded() {
+        let mut persistence = Persistence::new(FakeFlash::new());
+        persistence.load().unwrap();
+        persistence.save(&settings(1_000)).unwrap();
+        persistence.save(&settings(2_000)).unwrap();
+        persistence.save(&settings(3_000)).unwrap();
+
+        // A fresh instance sees the same data as after a power cycle
+        let mut reloaded = Persistence::new(persistence.storage);
+        assert_eq!(reloaded.load(), Ok(Some(settings(3_000))));
+    }
+
+    #[test]
+    fn wraps_around_pages_evenly() {
+        let mut persistence = Persistence::new(FakeFlash::new());
+        persistence.load().unwrap();
+        let slots = PAGE_SIZE * PAGE_COUNT / RECORD_SIZE;
+        for i in 0..slots * 10 {
+            persistence.save(&settings(5_000 + i as u32)).unwrap();
+
+            let mut reloaded = Persistence::new(FakeFlash {
+                data: persistence.storage.data,
+                erase_counts: [0; PAGE_COUNT],
+            });
+            assert_eq!(reloaded.load(), Ok(Some(settings(5_000 + i as u32))));
+        }
+
+        assert_eq!(persistence.storage().erase_counts, [10, 10]);
+    }
+
+    #[test]
+    fn interrupted_write_keeps_previous_settings() {
+        let mut persistence = Persistence::new(FakeFlash::new());
+        persistence.load().unwrap();
+        persistence.save(&settings(5_000)).unwrap();
+
+        // Half of the next record made it to flash before the power went out
+        let record = encode(&settings(6_000), 1);
+        let mut storage = persistence.storage;
+        storage.write(RECORD_SIZE, &record[..RECORD_SIZE / 2]).unwrap();
+
+        let mut reloaded = Persistence::new(storage);
+        assert_eq!(reloaded.load(), Ok(Some(settings(5_000))));
+
+        // The partially written slot is skipped on the next save
+        reloaded.save(&settings(7_000)).unwrap();
+        let mut reloaded = Persistence::new(reloaded.storage);
+        assert_eq!(reloaded.load(), Ok(Some(settings(7_000))));
+    }
+
+    /// Polls `settings(millivolts)` for each value, one ms apart from `start`
+    fn poll_trace(delayed: &mut DelayedSave, start: u32, trace: &[u32]) -> Option<u32> {
+        let mut saved = None;
+        for (i, &millivolts) in trace.iter().enumerate() {
+            if let Some(settings) = delayed.poll(&settings(millivolts), start.wrapping_add(i as u32)) {
+                assert_eq!(saved, None, "Saved twice");
+                assert_eq!(settings, self::settings(millivolts));
+                saved = Some(i as u32);
+            }
+        }
+        saved
+    }
+
+    #[test]
+    fn unchanged_settings_are_not_saved() {
+        let mut delayed = DelayedSave::new(settings(5_000));
+        assert_eq!(poll_trace(&mut delayed, 0, &[5_000; 100]), None);
+        assert_eq!(delayed.poll(&settings(5_000), 100_000), None);
+    }
+
+    #[test]
+    fn changes_are_saved_once_they_settle() {
+        let mut delayed = DelayedSave::new(settings(5_000));
+        let trace = [6_000; SAVE_DELAY as usize + 10];
+        assert_eq!(poll_trace(&mut delayed, 0, &trace), Some(SAVE_DELAY));
+        assert_eq!(poll_trace(&mut delayed, SAVE_DELAY + 10, &trace), None);
+    }
+
+    #[test]
+    fn a_sweep_is_saved_once_at_the_end() {
+        let mut delayed = DelayedSave::new(settings(5_000));
+        let mut trace = [0; 3 * SAVE_DELAY as usize];
+        // A new setpoint every ms for a while, then the last one is left alone
+        for (i, millivolts) in trace.iter_mut().enumerate() {
+            *millivolts = 1_000 + i.min(SAVE_DELAY as usize) as u32;
+        }
+        assert_eq!(poll_trace(&mut delayed, 0, &trace), Some(2 * SAVE_DELAY));
+    }
+
+    #[test]
+    fn changing_back_cancels_the_save() {
+        let mut delayed = DelayedSave::new(settings(5_000));
+        assert_eq!(poll_trace(&mut delayed, 0, &[6_000; 10]), None);
+        assert_eq!(poll_trace(&mut delayed, 10, &[5_000; SAVE_DELAY as usize * 2]), None);
+    }
+
+    #[test]
+    fn immediate_saves_are_not_repeated() {
+        let mut delayed = DelayedSave::new(settings(5_000));
+        delayed.poll(&settings(6_000), 0);
+        assert!(!delayed.is_saved(&settings(6_000)));
+        delayed.saved(settings(6_000));
+        assert!(delayed.is_saved(&settings(6_000)));
+        assert_eq!(delayed.poll(&settings(6_000), 2 * SAVE_DELAY), None);
+    }
+
+    #[test]
+    fn delay_survives_the_clock_wrapping() {
+        let mut delayed = DelayedSave::new(settings(5_000));
+        let start = u32::MAX - 10;
+        assert_eq!(poll_trace(&mut delayed, start, &[6_000; SAVE_DELAY as usize]), None);
+        assert!(delayed.poll(&settings(6_000), start.wrapping_add(SAVE_DELAY)).is_some());
+    }
+
+    #[test]
+    fn default_settings_round_trip() {
+        let record = encode(&Settings::default(), 0);
+        assert_eq!(decode(&record), Ok((0, Settings::default())));
+    }
+}
@@ -0,0 +1,206 @@
+/*!
+  Latching output protection.
+
+  Once a fault trips, the output stays off until the fault is reset from the
+  keypad or the remote port, even if the cause has gone away. Resetting only
+  re-arms the output, it has to be turned on again before it is live.
+
+  Over-current trips only once the current has been above the limit for a
+  while, since the current limiter needs some time to catch load steps. A
+  limit of 0 A turns over-current protection off. The current limiter holds
+  the output at 0 A then, so all the measurement shows is the offset and
+  noise of the ADC, which would trip it as soon as the output is turned on.
+  Over-voltage trips on the first sample above the threshold.
+*/
+
+use units::MilliVolts;
+
+/// How long the current may exceed the limit before the output trips, in ms
+pub const DEFAULT_OVER_CURRENT_DELAY: u32 = 10;
+/// Over-voltage threshold, just above the highest setpoint
+pub const DEFAULT_OVER_VOLTAGE: MilliVolts = MilliVolts(21_000);
+
+/// Why the output was turned off
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    OverCurrent,
+    OverVoltage,
+}
+
+impl Fault {
+    /// Shown on the status line while the fault is latched
+    pub fn message(&self) -> &'static str {
+        match *self {
+            Fault::OverCurrent => "OCP TRIP",
+            Fault::OverVoltage => "OVP TRIP",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Protection {
+    over_current_delay: u32,
+    /// How long the current has been above the limit without a break
+    over_current_time: u32,
+    fault: Option<Fault>,
+}
+
+impl Protection {
+    pub fn new(over_current_delay: u32) -> Self {
+        Self {
+            over_current_delay,
+            over_current_time: 0,
+            fault: None,
+        }
+    }
+
+    pub fn over_current_delay(&self) -> u32 {
+        self.over_current_delay
+    }
+
+    pub fn set_over_current_delay(&mut self, delay: u32) {
+        self.over_current_delay = delay;
+    }
+
+    /**
+      Checks a current measured over the last `elapsed` ms. Returns the fault
+      if this measurement tripped it. Never trips with a limit of 0 A.
+    */
+    pub fn check_current(&mut self, current: f32, limit: f32, elapsed: u32) -> Option<Fault> {
+        if self.fault.is_some() {
+            return None;
+        }
+        if limit > 0. && current > limit {
+            self.over_current_time = self.over_current_time.saturating_add(elapsed);
+            if self.over_current_time >= self.over_current_delay {
+                self.fault = Some(Fault::OverCurrent);
+                return self.fault;
+            }
+        }
+        else {
+            self.over_current_time = 0;
+        }
+        None
+    }
+
+    /// Checks a measured voltage. Returns the fault if it tripped
+    pub fn check_voltage(&mut self, voltage: f32, threshold: f32) -> Option<Fault> {
+        if self.fault.is_none() && voltage > threshold {
+            self.fault = Some(Fault::OverVoltage);
+            return self.fault;
+        }
+        None
+    }
+
+    /// The latched fault, if any
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    /// Clears the latched fault so the output can be turned on again
+    pub fn reset(&mut self) {
+        self.fault = None;
+        self.over_current_time = 0;
+    }
+}
+
+impl Default for Protection {
+    fn default() -> Self {
+        Self::new(DEFAULT_OVER_CURRENT_DELAY)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: f32 = 1.;
+
+    /**
+      Feeds a current trace sampled every ms and returns the time at which the
+      protection tripped
+    */
+    fn trip_time(protection: &mut Protection, trace: &[f32]) -> Option<usize> {
+        trace.iter()
+            .position(|&current| protection.check_current(current, LIMIT, 1).is_some())
+    }
+
+    #[test]
+    fn currents_within_the_limit_never_trip() {
+        let mut protection = Protection::new(5);
+        assert_eq!(trip_time(&mut protection, &[0., 0.5, 1., 0.99, 1., 1., 1., 1., 1., 1.]), None);
+        assert_eq!(protection.fault(), None);
+    }
+
+    #[test]
+    fn sustained_over_current_trips_after_the_delay() {
+        let mut protection = Protection::new(5);
+        let trace = [0.5, 0.5, 1.5, 1.5, 1.5, 1.5, 1.5, 1.5, 1.5];
+        assert_eq!(trip_time(&mut protection, &trace), Some(6));
+        assert_eq!(protection.fault(), Some(Fault::OverCurrent));
+    }
+
+    #[test]
+    fn short_spikes_are_ignored() {
+        let mut protection = Protection::new(5);
+        let trace = [2., 2., 2., 2., 0.5, 2., 2., 2., 2., 0.9, 3., 3., 3., 3.];
+        assert_eq!(trip_time(&mut protection, &trace), None);
+    }
+
+    #[test]
+    fn trip_latches_until_reset() {
+        let mut protection = Protection::new(2);
+        assert_eq!(trip_time(&mut protection, &[2., 2.]), Some(1));
+        // Only the measurement that trips reports it
+        assert_eq!(trip_time(&mut protection, &[0., 2., 2., 0.]), None);
+        assert_eq!(protection.fault(), Some(Fault::OverCurrent));
+
+        protection.reset();
+        assert_eq!(protection.fault(), None);
+        assert_eq!(trip_time(&mut protection, &[2., 0., 2., 2.]), Some(3));
+    }
+
+    #[test]
+    fn elapsed_time_adds_up() {
+        let mut protection = Protection::new(10);
+        assert_eq!(protection.check_current(1.5, LIMIT, 4), None);
+        assert_eq!(protection.check_current(1.5, LIMIT, 4), None);
+        assert_eq!(protection.check_current(1.5, LIMIT, 4), Some(Fault::OverCurrent));
+    }
+
+    #[test]
+    fn over_voltage_trips_on_the_first_sample() {
+        let mut protection = Protection::default();
+        assert_eq!(protection.check_voltage(12., 12.), None);
+        assert_eq!(protection.check_voltage(12.01, 12.), Some(Fault::OverVoltage));
+        assert_eq!(protection.check_voltage(0., 12.), None);
+        assert_eq!(protection.fault(), Some(Fault::OverVoltage));
+    }
+
+    #[test]
+    fn first_fault_is_kept() {
+        let mut protection = Protection::new(0);
+        assert_eq!(protection.check_voltage(15., 12.), Some(Fault::OverVoltage));
+        assert_eq!(protection.check_current(2., LIMIT, 1), None);
+        assert_eq!(protection.fault(), Some(Fault::OverVoltage));
+        protection.reset();
+        assert_eq!(protection.check_current(2., LIMIT, 1), Some(Fault::OverCurrent));
+    }
+
+    #[test]
+    fn zero_limit_never_trips() {
+        let mut protection = Protection::new(0);
+        let noise = [0.002, 0.004, 0.001, 0.006, 0.003, 0.005];
+        for &current in noise.iter().cycle().take(1000) {
+            assert_eq!(protection.check_current(current, 0., 1), None);
+        }
+        assert_eq!(protection.fault(), None);
+    }
+
+    #[test]
+    fn zero_delay_trips_at_once() {
+        let mut protection = Protection::new(0);
+        assert_eq!(trip_time(&mut protection, &[0.5, 1.01]), Some(1));
+    }
+}
@@ -0,0 +1,141 @@
+/*!
+  Slew rate limiting of the voltage setpoint.
+
+  Jumping straight to a new setpoint makes the output overshoot and sends a
+  large inrush current into capacitive loads. The ramp instead moves towards
+  the setpoint at a limited rate every control period.
+*/
+
+/// Default slew rate in mV/ms, a full swing takes about 2 s
+pub const DEFAULT_SLEW_RATE: u32 = 10;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ramp {
+    /// mV/ms
+    rate: u32,
+    /// V
+    value: f32,
+}
+
+impl Ramp {
+    pub fn new(rate: u32, value: f32) -> Self {
+        Self { rate, value }
+    }
+
+    /// In mV/ms
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: u32) {
+        self.rate = rate;
+    }
+
+    /// The current value of the ramp
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+
+    /**
+      Moves the ramp `elapsed` ms towards `target` and returns the new value.
+      The ramp stops exactly at the target.
+    */
+    pub fn step(&mut self, target: f32, elapsed: u32) -> f32 {
+        let max_step = self.rate.saturating_mul(elapsed) as f32 / 1000.;
+        self.value = if self.value < target {
+            (self.value + max_step).min(target)
+        }
+        else {
+            (self.value - max_step).max(target)
+        };
+        self.value
+    }
+
+    /// Jumps to `value` without ramping
+    pub fn reset(&mut self, value: f32) {
+        self.value = value;
+    }
+}
+
+impl Default for Ramp {
+    fn default() -> Self {
+        Self::new(DEFAULT_SLEW_RATE, 0.)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+      Steps the ramp every ms until it reaches `target`, checking that it
+      moves the whole way in one direction at the rate. Returns the number of
+      steps taken
+    */
+    fn ramp_to(ramp: &mut Ramp, target: f32) -> usize {
+        let mut steps = 0;
+        let rising = ramp.value() < target;
+        while ramp.value() != target {
+            let previous = ramp.value();
+            let value = ramp.step(target, 1);
+            if rising {
+                assert!(value > previous && value <= target, "{} -> {}", previous, value);
+            }
+            else {
+                assert!(value < previous && value >= target, "{} -> {}", previous, value);
+            }
+            assert!((value - previous).abs() <= ramp.rate() as f32 / 1000. * 1.0001);
+            steps += 1;
+            assert!(steps < 100_000, "The ramp never reached {}", target);
+        }
+        steps
+    }
+
+    #[test]
+    fn ramps_up_monotonically() {
+        let mut ramp = Ramp::new(10, 0.);
+        assert_eq!(ramp_to(&mut ramp, 5.), 500);
+        assert_eq!(ramp.value(), 5.);
+    }
+
+    #[test]
+    fn ramps_down_monotonically() {
+        let mut ramp = Ramp::new(100, 12.);
+        assert_eq!(ramp_to(&mut ramp, 3.3), 87);
+        assert_eq!(ramp.value(), 3.3);
+    }
+
+    #[test]
+    fn stays_at_the_target() {
+        let mut ramp = Ramp::new(500, 4.9);
+        assert_eq!(ramp.step(5., 1), 5.);
+        assert_eq!(ramp.step(5., 1), 5.);
+        assert_eq!(ramp.step(5., 100), 5.);
+    }
+
+    #[test]
+    fn elapsed_time_scales_the_step() {
+        let mut ramp = Ramp::new(250, 0.);
+        assert_eq!(ramp.step(10., 4), 1.);
+        assert_eq!(ramp.step(10., 0), 1.);
+    }
+
+    #[test]
+    fn target_can_change_mid_ramp() {
+        let mut ramp = Ramp::new(500, 0.);
+        for _ in 0..10 {
+            ramp.step(20., 1);
+        }
+        assert_eq!(ramp.value(), 5.);
+        ramp_to(&mut ramp, 2.);
+        assert_eq!(ramp.value(), 2.);
+    }
+
+    #[test]
+    fn reset_jumps() {
+        let mut ramp = Ramp::new(10, 12.);
+        ramp.reset(0.);
+        assert_eq!(ramp.value(), 0.);
+    }
+}
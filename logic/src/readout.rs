@@ -0,0 +1,176 @@
+/*!
+  Live readout of the measured output for the status line.
+
+  The control loop measures far faster than the display can be read, so the
+  measurements are averaged over each refresh period. This also hides most of
+  the ADC noise.
+*/
+
+use arrayvec::ArrayString;
+use itoa;
+
+use menu::LINE_LENGTH;
+use state::push_milli_units;
+
+/// How often the readout is refreshed, in ms
+pub const REFRESH_PERIOD: u32 = 250;
+
+/// Averaged measurements of the output
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Reading {
+    pub voltage: f32,
+    pub current: f32,
+}
+
+impl Reading {
+    pub fn power(&self) -> f32 {
+        self.voltage * self.current
+    }
+
+    /**
+      Writes the reading for a line of the display, "5.00V 1.00A 5.0W". The
+      power loses its decimal when the line would be too long otherwise,
+      "12.00V 2.50A 30W"
+    */
+    pub fn push_display(&self, result: &mut ArrayString<[u8; 32]>) {
+        let mut line = ArrayString::<[u8; 32]>::new();
+        self.push_voltage_and_current(&mut line);
+
+        // Tenths of a watt
+        let power = (self.power() * 10. + 0.5) as u32;
+        let mut buffer = itoa::Buffer::new();
+        let whole = buffer.format(power / 10);
+        // Whole watts, the decimal point, tenths and the unit
+        if line.len() + whole.len() + 3 <= LINE_LENGTH {
+            line.push_str(whole);
+            line.push('.');
+            line.push_str(buffer.format(power % 10));
+        }
+        else {
+            line.push_str(buffer.format((power + 5) / 10));
+        }
+        line.push('W');
+        result.push_str(&line);
+    }
+
+    /// Writes the voltage and current without the power, "12.00V 2.50A "
+    pub fn push_voltage_and_current(&self, result: &mut ArrayString<[u8; 32]>) {
+        push_milli_units(result, to_thousandths(self.voltage), "V ");
+        push_milli_units(result, to_thousandths(self.current), "A ");
+    }
+}
+
+/// Negative values saturate at 0
+fn to_thousandths(value: f32) -> u32 {
+    (value * 1000.) as u32
+}
+
+/// Averages measurements into a `Reading` once per refresh period
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Readout {
+    voltage_sum: f32,
+    current_sum: f32,
+    samples: u32,
+    elapsed: u32,
+    reading: Reading,
+}
+
+impl Readout {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+      Adds measurements taken `elapsed` ms after the previous ones. Returns
+      true when a refresh period has passed and the reading was updated
+    */
+    pub fn add(&mut self, voltage: f32, current: f32, elapsed: u32) -> bool {
+        self.voltage_sum += voltage;
+        self.current_sum += current;
+        self.samples += 1;
+        self.elapsed += elapsed;
+        if self.elapsed < REFRESH_PERIOD {
+            return false;
+        }
+
+        let samples = self.samples as f32;
+        self.reading = Reading {
+            voltage: self.voltage_sum / samples,
+            current: self.current_sum / samples,
+        };
+        self.voltage_sum = 0.;
+        self.current_sum = 0.;
+        self.samples = 0;
+        self.elapsed = 0;
+        true
+    }
+
+    /// The reading from the last complete refresh period
+    pub fn reading(&self) -> Reading {
+        self.reading
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn display(voltage: f32, current: f32) -> ArrayString<[u8; 32]> {
+        let mut result = ArrayString::new();
+        Reading { voltage, current }.push_display(&mut result);
+        result
+    }
+
+    #[test]
+    fn formatting() {
+        assert_eq!(display(5., 1.).as_str(), "5.00V 1.00A 5.0W");
+        assert_eq!(display(3.3, 0.25).as_str(), "3.30V 0.25A 0.8W");
+        assert_eq!(display(0., 0.).as_str(), "0.00V 0.00A 0.0W");
+    }
+
+    #[test]
+    fn long_lines_drop_the_power_decimal() {
+        assert_eq!(display(12., 0.5).as_str(), "12.00V 0.50A 6W");
+        assert_eq!(display(20., 2.5).as_str(), "20.00V 2.50A 50W");
+        assert_eq!(display(9.5, 2.).as_str(), "9.50V 2.00A 19W");
+    }
+
+    #[test]
+    fn every_reading_fits_on_a_line() {
+        for &voltage in &[0., 0.999, 5., 9.99, 10., 19.99, 25.] {
+            for &current in &[0., 0.05, 0.999, 1., 2.5, 3.] {
+                let line = display(voltage, current);
+                assert!(line.len() <= LINE_LENGTH, "{}", line);
+            }
+        }
+    }
+
+    #[test]
+    fn negative_noise_reads_as_zero() {
+        assert_eq!(display(-0.01, -0.001).as_str(), "0.00V 0.00A 0.0W");
+    }
+
+    #[test]
+    fn readings_are_averaged_over_the_refresh_period() {
+        let mut readout = Readout::new();
+        for i in 0..REFRESH_PERIOD - 1 {
+            let noise = if i % 2 == 0 { 0.1 } else { -0.1 };
+            assert!(!readout.add(5. + noise, 1., 1));
+        }
+        assert_eq!(readout.reading(), Reading::default());
+        assert!(readout.add(5., 1., 1));
+        let reading = readout.reading();
+        assert!((reading.voltage - 5.).abs() < 0.001, "{}", reading.voltage);
+        assert_eq!(reading.current, 1.);
+    }
+
+    #[test]
+    fn each_period_starts_over() {
+        let mut readout = Readout::new();
+        assert!(readout.add(12., 2., REFRESH_PERIOD));
+        assert!(!readout.add(3., 1., REFRESH_PERIOD / 2));
+        assert!(readout.add(5., 1., REFRESH_PERIOD / 2));
+        assert_eq!(readout.reading(), Reading { voltage: 4., current: 1. });
+    }
+}
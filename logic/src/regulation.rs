@@ -0,0 +1,151 @@
+/*!
+  Detection of which quantity the output is regulating.
+
+  While the load draws less than the current limit the output holds the voltage
+  setpoint (CV). Once the load wants more, the current is held at the limit and
+  the voltage sags instead (CC). The mode is decided from the measured current
+  relative to the limit, with a gap between the thresholds for entering and
+  leaving CC so that noise around the limit does not make the display flicker.
+
+  With a limit of 0 A the output is always shown in CV, the current protection
+  is off then and any measured current is just noise.
+*/
+
+/// Fraction of the current limit above which the output is in CC
+pub const ENTER_CONSTANT_CURRENT: f32 = 0.98;
+/// Fraction of the current limit below which the output is back in CV
+pub const LEAVE_CONSTANT_CURRENT: f32 = 0.95;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    ConstantVoltage,
+    ConstantCurrent,
+}
+
+impl Mode {
+    /// Shown on the status line and returned by the remote port
+    pub fn label(&self) -> &'static str {
+        match *self {
+            Mode::ConstantVoltage => "CV",
+            Mode::ConstantCurrent => "CC",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModeDetector {
+    mode: Mode,
+}
+
+impl ModeDetector {
+    pub fn new() -> Self {
+        Self { mode: Mode::ConstantVoltage }
+    }
+
+    /// Updates the mode from a current measurement
+    pub fn update(&mut self, current: f32, limit: f32) -> Mode {
+        if limit <= 0. {
+            self.mode = Mode::ConstantVoltage;
+            return self.mode;
+        }
+        self.mode = match self.mode {
+            Mode::ConstantVoltage if current >= limit * ENTER_CONSTANT_CURRENT => {
+                Mode::ConstantCurrent
+            }
+            Mode::ConstantCurrent if current < limit * LEAVE_CONSTANT_CURRENT => {
+                Mode::ConstantVoltage
+            }
+            mode => mode,
+        };
+        self.mode
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Goes back to CV, for when the output is turned off
+    pub fn reset(&mut self) {
+        self.mode = Mode::ConstantVoltage;
+    }
+}
+
+impl Default for ModeDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: f32 = 1.;
+
+    /// Feeds a current trace and counts how often the mode changed
+    fn changes(detector: &mut ModeDetector, trace: &[f32]) -> usize {
+        let mut previous = detector.mode();
+        let mut changes = 0;
+        for &current in trace {
+            let mode = detector.update(current, LIMIT);
+            if mode != previous {
+                changes += 1;
+            }
+            previous = mode;
+        }
+        changes
+    }
+
+    #[test]
+    fn light_load_is_constant_voltage() {
+        let mut detector = ModeDetector::new();
+        assert_eq!(changes(&mut detector, &[0., 0.2, 0.5, 0.9, 0.97]), 0);
+        assert_eq!(detector.mode(), Mode::ConstantVoltage);
+    }
+
+    #[test]
+    fn current_at_the_limit_is_constant_current() {
+        let mut detector = ModeDetector::new();
+        assert_eq!(detector.update(0.5, LIMIT), Mode::ConstantVoltage);
+        assert_eq!(detector.update(1., LIMIT), Mode::ConstantCurrent);
+        assert_eq!(detector.update(0.5, LIMIT), Mode::ConstantVoltage);
+    }
+
+    #[test]
+    fn noise_around_the_limit_does_not_flicker() {
+        let mut detector = ModeDetector::new();
+        let trace = [0.99, 0.97, 1.01, 0.96, 0.99, 0.955, 1., 0.97];
+        assert_eq!(changes(&mut detector, &trace), 1);
+        assert_eq!(detector.mode(), Mode::ConstantCurrent);
+
+        let trace = [0.94, 0.96, 0.97, 0.95, 0.975];
+        assert_eq!(changes(&mut detector, &trace), 1);
+        assert_eq!(detector.mode(), Mode::ConstantVoltage);
+    }
+
+    #[test]
+    fn zero_limit_is_constant_voltage() {
+        let mut detector = ModeDetector::new();
+        for &current in &[0., 0.002, 0.004, 0.001, 0.003] {
+            assert_eq!(detector.update(current, 0.), Mode::ConstantVoltage);
+        }
+        // Also when the limit is lowered to 0 while in CC
+        assert_eq!(detector.update(1., LIMIT), Mode::ConstantCurrent);
+        assert_eq!(detector.update(0.003, 0.), Mode::ConstantVoltage);
+    }
+
+    #[test]
+    fn reset_returns_to_constant_voltage() {
+        let mut detector = ModeDetector::new();
+        detector.update(2., LIMIT);
+        detector.reset();
+        assert_eq!(detector.mode(), Mode::ConstantVoltage);
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(Mode::ConstantVoltage.label(), "CV");
+        assert_eq!(Mode::ConstantCurrent.label(), "CC");
+    }
+}
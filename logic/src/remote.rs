@@ -0,0 +1,876 @@
+/*!
+  Remote control over a serial line using a subset of SCPI.
+
+  Each line holds one command. Headers are case insensitive and can be given
+  in their short or long form, `VOLT` or `VOLTage`.
+
+  | Command                   | Effect                                           |
+  |---------------------------|--------------------------------------------------|
+  | `*IDN?`                   | Identifies the supply                            |
+  | `VOLT <volts>`            | Sets the voltage setpoint                        |
+  | `VOLT?`                   | Returns the voltage setpoint                     |
+  | `CURR <amps>`             | Sets the current limit                           |
+  | `CURR?`                   | Returns the current limit                        |
+  | `OUTP ON\|OFF`            | Turns the output on or off, `1` and `0` work too |
+  | `OUTP?`                   | Returns 1 if the output is live, else 0          |
+  | `MEAS:VOLT?`              | Returns the measured output voltage              |
+  | `OUTP:MODE?`              | Returns `CV` or `CC` while the output is live,   |
+  |                           | else `OFF`                                       |
+  | `SYST:ERR?`               | Returns the oldest error, `0,"No error"` if none |
+  | `CURR:PROT:DEL <seconds>` | Sets the over-current trip delay                 |
+  | `CURR:PROT:DEL?`          | Returns the over-current trip delay              |
+  | `CURR:PROT:TRIP?`         | Returns 1 if over-current protection tripped     |
+  | `VOLT:PROT <volts>`       | Sets the over-voltage threshold                  |
+  | `VOLT:PROT?`              | Returns the over-voltage threshold               |
+  | `VOLT:PROT:TRIP?`         | Returns 1 if over-voltage protection tripped     |
+  | `VOLT:SLEW <volts per ms>`| Sets how fast the output moves to a new voltage  |
+  | `VOLT:SLEW?`              | Returns the slew rate in V/ms                    |
+  | `OUTP:PROT:CLE`           | Clears a tripped protection, output stays off    |
+
+  Settings are turned into the same `interface::Command`s as the keypad
+  sends, and the setpoints go through the same limits. The protection and
+  slew rate settings can only be changed from here, so their limits are
+  defined below. Unlike keypad changes, remote changes are only saved across
+  power cycles once they have been left alone for `persistence::SAVE_DELAY`,
+  so scripts sweeping the setpoints don't wear out the flash.
+*/
+
+use arrayvec::{ArrayString, ArrayVec};
+use itoa;
+
+use interface::{Command, Limits, Quantity};
+use state::State;
+use protection::Fault;
+use units::{self, MilliAmps, MilliVolts};
+
+/// The longest line that is accepted, without the line ending
+pub const MAX_LINE: usize = 64;
+/// How many errors are kept until they are read with `SYST:ERR?`
+pub const ERROR_QUEUE: usize = 8;
+/// How many bytes of responses can wait to be sent
+pub const OUTPUT_QUEUE: usize = 4 * MAX_LINE;
+
+/// Over-current trip delay in ms
+pub const OVER_CURRENT_DELAY_LIMITS: Limits = Limits { min: 0, max: 10_000 };
+/// Over-voltage threshold in mV, up to the full scale of the voltage measurement
+pub const OVER_VOLTAGE_LIMITS: Limits = Limits { min: 0, max: 25_000 };
+/// Slew rate in mV/ms. The fastest rate reaches any voltage in one control period
+pub const SLEW_RATE_LIMITS: Limits = Limits { min: 1, max: 25_000 };
+
+/// Returned by `*IDN?`: manufacturer, model, serial number and firmware version
+pub const IDENTITY: &str = concat!("TheZoq2,PSU,0,", env!("CARGO_PKG_VERSION"));
+
+/// Responses to one line, each terminated by a newline
+pub type Response = ArrayString<[u8; MAX_LINE]>;
+
+/// SCPI errors, numbered like the standard error queue
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The line contained characters that are not printable ASCII
+    InvalidCharacter,
+    ParameterNotAllowed,
+    MissingParameter,
+    UndefinedHeader,
+    /// The parameter is not a number
+    NumericData,
+    DataOutOfRange,
+    IllegalParameterValue,
+    /// Errors were lost because the queue was full
+    QueueOverflow,
+    /// The serial port reported noise on the line
+    CommunicationError,
+    ParityError,
+    FramingError,
+    /// The line was longer than `MAX_LINE`, or the serial port lost bytes
+    InputBufferOverrun,
+    /// A response was dropped because the earlier ones had not been sent yet
+    QueryInterrupted,
+}
+
+impl Error {
+    pub fn code(&self) -> i16 {
+        match *self {
+            Error::InvalidCharacter => -101,
+            Error::ParameterNotAllowed => -108,
+            Error::MissingParameter => -109,
+            Error::UndefinedHeader => -113,
+            Error::NumericData => -120,
+            Error::DataOutOfRange => -222,
+            Error::IllegalParameterValue => -224,
+            Error::QueueOverflow => -350,
+            Error::CommunicationError => -360,
+            Error::ParityError => -361,
+            Error::FramingError => -362,
+            Error::InputBufferOverrun => -363,
+            Error::QueryInterrupted => -410,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match *self {
+            Error::InvalidCharacter => "Invalid character",
+            Error::ParameterNotAllowed => "Parameter not allowed",
+            Error::MissingParameter => "Missing parameter",
+            Error::UndefinedHeader => "Undefined header",
+            Error::NumericData => "Numeric data error",
+            Error::DataOutOfRange => "Data out of range",
+            Error::IllegalParameterValue => "Illegal parameter value",
+            Error::QueueOverflow => "Queue overflow",
+            Error::CommunicationError => "Communication error",
+            Error::ParityError => "Parity error in program message",
+            Error::FramingError => "Framing error in program message",
+            Error::InputBufferOverrun => "Input buffer overrun",
+            Error::QueryInterrupted => "Query INTERRUPTED",
+        }
+    }
+}
+
+/// Values that can be read back
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Query {
+    Identity,
+    Voltage,
+    Current,
+    Output,
+    MeasuredVoltage,
+    /// Removes the oldest error from the queue
+    Error,
+    OverCurrentDelay,
+    OverCurrentTripped,
+    OverVoltage,
+    OverVoltageTripped,
+    SlewRate,
+    /// CV or CC
+    Mode,
+}
+
+/// The mnemonics of a header in short and long form
+type Header = &'static [(&'static str, &'static str)];
+
+const HEADERS: &[(Header, Query)] = &[
+    (&[("*IDN", "*IDN")], Query::Identity),
+    (&[("VOLT", "VOLTAGE")], Query::Voltage),
+    (&[("CURR", "CURRENT")], Query::Current),
+    (&[("OUTP", "OUTPUT")], Query::Output),
+    (&[("MEAS", "MEASURE"), ("VOLT", "VOLTAGE")], Query::MeasuredVoltage),
+    (&[("SYST", "SYSTEM"), ("ERR", "ERROR")], Query::Error),
+    (&[("CURR", "CURRENT"), ("PROT", "PROTECTION"), ("DEL", "DELAY")], Query::OverCurrentDelay),
+    (&[("CURR", "CURRENT"), ("PROT", "PROTECTION"), ("TRIP", "TRIPPED")], Query::OverCurrentTripped),
+    (&[("VOLT", "VOLTAGE"), ("PROT", "PROTECTION")], Query::OverVoltage),
+    (&[("VOLT", "VOLTAGE"), ("PROT", "PROTECTION"), ("TRIP", "TRIPPED")], Query::OverVoltageTripped),
+    (&[("VOLT", "VOLTAGE"), ("SLEW", "SLEW")], Query::SlewRate),
+    (&[("OUTP", "OUTPUT"), ("MODE", "MODE")], Query::Mode),
+];
+
+/// A command without a parameter, so it has no query form
+const CLEAR_PROTECTION: Header = &[("OUTP", "OUTPUT"), ("PROT", "PROTECTION"), ("CLE", "CLEAR")];
+
+/// A parsed line
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    Command(Command),
+    Query(Query),
+}
+
+/**
+  Parses one line without its line ending. Leading and trailing whitespace is
+  ignored.
+*/
+pub fn parse(line: &str) -> Result<Request, Error> {
+    let line = line.trim();
+    let (header, parameter) = match line.find(|c: char| c.is_ascii_whitespace()) {
+        Some(split) => (&line[..split], Some(line[split..].trim_start())),
+        None => (line, None),
+    };
+    let (header, is_query) = match header.len().checked_sub(1) {
+        Some(last) if header.as_bytes()[last] == b'?' => (&header[..last], true),
+        _ => (header, false),
+    };
+    // A leading colon means the header starts from the root, which is the
+    // only place headers can start anyway
+    let header = header.trim_start_matches(':');
+
+    if !is_query && matches(header, CLEAR_PROTECTION) {
+        return match parameter {
+            Some(_) => Err(Error::ParameterNotAllowed),
+            None => Ok(Request::Command(Command::ResetProtection)),
+        };
+    }
+    let query = HEADERS.iter()
+        .find(|&&(pattern, _)| matches(header, pattern))
+        .map(|&(_, query)| query)
+        .ok_or(Error::UndefinedHeader)?;
+
+    if is_query {
+        return match parameter {
+            Some(_) => Err(Error::ParameterNotAllowed),
+            None => Ok(Request::Query(query)),
+        };
+    }
+
+    let parameter = parameter.ok_or(Error::MissingParameter)?;
+    let command = match query {
+        Query::Voltage => {
+            let limits = Quantity::Voltage.limits();
+            Command::Voltage(parse_number(parameter, MilliVolts::from_volts, limits)?)
+        }
+        Query::Current => {
+            let limits = Quantity::Current.limits();
+            Command::Current(parse_number(parameter, MilliAmps::from_amps, limits)?)
+        }
+        Query::OverCurrentDelay => {
+            // Seconds to ms
+            let delay = parse_number(parameter, units::thousandths, OVER_CURRENT_DELAY_LIMITS)?;
+            Command::OverCurrentDelay(delay)
+        }
+        Query::OverVoltage => {
+            Command::OverVoltage(parse_number(parameter, MilliVolts::from_volts, OVER_VOLTAGE_LIMITS)?)
+        }
+        Query::SlewRate => {
+            // V/ms to mV/ms
+            Command::SlewRate(parse_number(parameter, units::thousandths, SLEW_RATE_LIMITS)?)
+        }
+        Query::Output => {
+            if mnemonic(parameter, "ON", "1") {
+                Command::OutputOn
+            }
+            else if mnemonic(parameter, "OFF", "0") {
+                Command::OutputOff
+            }
+            else {
+                return Err(Error::IllegalParameterValue);
+            }
+        }
+        // The rest can only be queried
+        _ => return Err(Error::UndefinedHeader),
+    };
+    Ok(Request::Command(command))
+}
+
+/// Returns true if `header` is made up of the mnemonics in `pattern`
+fn matches(header: &str, pattern: Header) -> bool {
+    let mut mnemonics = header.split(':');
+    pattern.iter().all(|&(short, long)| {
+        mnemonics.next().map(|word| mnemonic(word, short, long)) == Some(true)
+    }) && mnemonics.next().is_none()
+}
+
+/// Returns true if `word` is the short or long form of a mnemonic
+fn mnemonic(word: &str, short: &str, long: &str) -> bool {
+    word.eq_ignore_ascii_case(short) || word.eq_ignore_ascii_case(long)
+}
+
+/**
+  Parses a value in whole units and converts it to thousandths with
+  `from_units`, if it is within `limits`. The value is rounded to the nearest
+  thousandth, so values with up to three decimals are taken exactly as written
+*/
+fn parse_number<T>(parameter: &str, from_units: fn(f32) -> Option<T>, limits: Limits)
+    -> Result<T, Error>
+    where T: Copy, u32: From<T>
+{
+    let value = parameter.parse::<f32>().map_err(|_| Error::NumericData)?;
+    match from_units(value) {
+        Some(converted) if limits.contains(u32::from(converted)) => Ok(converted),
+        _ => Err(Error::DataOutOfRange),
+    }
+}
+
+/**
+  Pushes a non-negative value in whole units with three decimals. Negative
+  values are shown as 0
+*/
+fn push_units(result: &mut Response, value: f32) {
+    push_thousandths(result, units::thousandths(value).unwrap_or(0))
+}
+
+/// Pushes a value given in thousandths with three decimals
+fn push_thousandths(result: &mut Response, thousandths: u32) {
+    let mut buffer = itoa::Buffer::new();
+    result.push_str(buffer.format(thousandths / 1000));
+    result.push_str(".");
+    let fraction = thousandths % 1000;
+    if fraction < 100 {
+        result.push_str("0");
+    }
+    if fraction < 10 {
+        result.push_str("0");
+    }
+    result.push_str(buffer.format(fraction));
+}
+
+
+/**
+  Collects received bytes into lines and executes them.
+
+  Settings are returned as commands for the caller to apply to the state,
+  like the commands from the keypad. Errors are queued until `SYST:ERR?`.
+*/
+pub struct Remote {
+    line: ArrayString<[u8; MAX_LINE]>,
+    /// Set when the current line can't be executed. The rest of it is dropped
+    discard: Option<Error>,
+    errors: ArrayVec<[Error; ERROR_QUEUE]>,
+}
+
+impl Remote {
+    pub fn new() -> Self {
+        Self {
+            line: ArrayString::new(),
+            discard: None,
+            errors: ArrayVec::new(),
+        }
+    }
+
+    /**
+      Handles one received byte. Once a line ends it is executed and replies
+      are written to `response`.
+    */
+    pub fn receive(&mut self, byte: u8, state: &State, response: &mut Response) -> Option<Command> {
+        match byte {
+            b'\n' => {
+                let command = match self.discard.take() {
+                    Some(error) => {
+                        self.push_error(error);
+                        None
+                    }
+                    None => {
+                        let line = self.line;
+                        self.execute(&line, state, response)
+                    }
+                };
+                self.line.clear();
+                command
+            }
+            // Lines may end with \r\n
+            b'\r' => None,
+            _ if self.discard.is_some() => None,
+            b' '..=b'~' | b'\t' => {
+                if self.line.try_push(byte as char).is_err() {
+                    self.discard = Some(Error::InputBufferOverrun);
+                }
+                None
+            }
+            _ => {
+                self.discard = Some(Error::InvalidCharacter);
+                None
+            }
+        }
+    }
+
+    /**
+      Drops the line being received because some of it was lost, for example
+      to a framing error. The error is queued once the line ends
+    */
+    pub fn discard_line(&mut self, error: Error) {
+        if self.discard.is_none() {
+            self.discard = Some(error);
+        }
+    }
+
+    /// Executes a complete line. Empty lines are ignored
+    pub fn execute(&mut self, line: &str, state: &State, response: &mut Response) -> Option<Command> {
+        if line.trim().is_empty() {
+            return None;
+        }
+        let query = match parse(line) {
+            Ok(Request::Command(command)) => return Some(command),
+            Ok(Request::Query(query)) => query,
+            Err(error) => {
+                self.push_error(error);
+                return None;
+            }
+        };
+
+        match query {
+            Query::Identity => response.push_str(IDENTITY),
+            Query::Voltage => push_thousandths(response, state.voltage().0),
+            Query::Current => push_thousandths(response, state.current_limit().0),
+            Query::Output => response.push_str(if state.output_enabled() { "1" } else { "0" }),
+            Query::MeasuredVoltage => push_units(response, state.filtered_voltage()),
+            Query::OverCurrentDelay => {
+                let delay = state.protection().over_current_delay();
+                push_thousandths(response, delay)
+            }
+            Query::OverCurrentTripped => {
+                let tripped = state.protection().fault() == Some(Fault::OverCurrent);
+                response.push_str(if tripped { "1" } else { "0" })
+            }
+            Query::OverVoltage => push_thousandths(response, state.over_voltage().0),
+            Query::SlewRate => push_thousandths(response, state.slew_rate()),
+            Query::Mode => response.push_str(state.mode().map(|mode| mode.label()).unwrap_or("OFF")),
+            Query::OverVoltageTripped => {
+                let tripped = state.protection().fault() == Some(Fault::OverVoltage);
+                response.push_str(if tripped { "1" } else { "0" })
+            }
+            Query::Error => {
+                let (code, message) = if self.errors.is_empty() {
+                    (0, "No error")
+                }
+                else {
+                    let error = self.errors.remove(0);
+                    (error.code(), error.message())
+                };
+                let mut buffer = itoa::Buffer::new();
+                response.push_str(buffer.format(code));
+                response.push_str(",\"");
+                response.push_str(message);
+                response.push_str("\"");
+            }
+        }
+        response.push_str("\n");
+        None
+    }
+
+    /// How many errors are waiting to be read
+    pub fn error_count(&self) -> usize {
+        self.errors.len()
+    }
+
+    /// Queues an error. If the queue is full the newest error is replaced
+    pub fn push_error(&mut self, error: Error) {
+        if self.errors.is_full() {
+            self.errors.pop();
+            self.errors.push(Error::QueueOverflow);
+        }
+        else {
+            self.errors.push(error);
+        }
+    }
+}
+
+impl Default for Remote {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/**
+  Responses waiting to be sent, so that they can be sent a byte at a time as
+  the serial port becomes ready instead of waiting for it
+*/
+pub struct OutputQueue {
+    bytes: [u8; OUTPUT_QUEUE],
+    /// Index of the oldest byte
+    start: usize,
+    len: usize,
+}
+
+impl OutputQueue {
+    pub fn new() -> Self {
+        Self {
+            bytes: [0; OUTPUT_QUEUE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /**
+      Queues a whole response. Returns false and queues nothing if it doesn't
+      fit.
+    */
+    pub fn push(&mut self, response: &str) -> bool {
+        if response.len() > OUTPUT_QUEUE - self.len {
+            return false;
+        }
+        for &byte in response.as_bytes() {
+            self.bytes[(self.start + self.len) % OUTPUT_QUEUE] = byte;
+            self.len += 1;
+        }
+        true
+    }
+
+    /// The next byte to send
+    pub fn front(&self) -> Option<u8> {
+        if self.len == 0 {
+            None
+        }
+        else {
+            Some(self.bytes[self.start])
+        }
+    }
+
+    /// Removes the next byte once it has been sent
+    pub fn pop(&mut self) -> Option<u8> {
+        let byte = self.front()?;
+        self.start = (self.start + 1) % OUTPUT_QUEUE;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for OutputQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Output of feeding a byte stream to a supply
+    struct Session {
+        responses: ArrayString<[u8; 512]>,
+        commands: ArrayVec<[Command; 8]>,
+    }
+
+    /// Feeds `bytes` to `remote`, applying the commands to `state`
+    fn feed(remote: &mut Remote, state: &mut State, bytes: &[u8]) -> Session {
+        let mut session = Session { responses: ArrayString::new(), commands: ArrayVec::new() };
+        for &byte in bytes {
+            let mut response = Response::new();
+            if let Some(command) = remote.receive(byte, state, &mut response) {
+                state.apply_command(command.clone());
+                session.commands.push(command);
+            }
+            session.responses.push_str(&response);
+        }
+        session
+    }
+
+    fn run(bytes: &[u8]) -> Session {
+        feed(&mut Remote::new(), &mut State::new(false), bytes)
+    }
+
+    fn responses(bytes: &[u8]) -> ArrayString<[u8; 512]> {
+        run(bytes).responses
+    }
+
+    #[test]
+    fn every_setpoint_reads_back_exactly() {
+        let mut remote = Remote::new();
+        let mut state = State::new(false);
+        let settings = [
+            (Quantity::Voltage.limits(), "VOLT"),
+            (Quantity::Current.limits(), "CURR"),
+            (OVER_VOLTAGE_LIMITS, "VOLT:PROT"),
+        ];
+        for &(limits, header) in &settings {
+            for thousandths in limits.min..=limits.max {
+                let mut value = Response::new();
+                push_thousandths(&mut value, thousandths);
+
+                let mut line = Response::from(header).unwrap();
+                line.push(' ');
+                line.push_str(&value);
+                let command = remote.execute(&line, &state, &mut Response::new());
+                assert!(command.is_some(), "{}", line);
+                state.apply_command(command.unwrap());
+
+                let mut query = Response::from(header).unwrap();
+                query.push('?');
+                let mut response = Response::new();
+                remote.execute(&query, &state, &mut response);
+                assert_eq!(response.trim_end(), value.as_str());
+            }
+        }
+    }
+
+    #[test]
+    fn identify() {
+        assert_eq!(responses(b"*IDN?\n").as_str(), "TheZoq2,PSU,0,0.1.0\n");
+        assert_eq!(responses(b"*idn?\r\n").as_str(), "TheZoq2,PSU,0,0.1.0\n");
+    }
+
+    #[test]
+    fn set_and_query_voltage() {
+        let session = run(b"VOLT 5.0\nVOLT?\n");
+        assert_eq!(session.commands.as_slice(), &[Command::Voltage(MilliVolts(5000))]);
+        assert_eq!(session.responses.as_str(), "5.000\n");
+    }
+
+    #[test]
+    fn set_and_query_current() {
+        let session = run(b"CURR 0.5\nCURR?\nCURR 0.025\nCURR?\n");
+        assert_eq!(session.commands.as_slice(), &[Command::Current(MilliAmps(500)), Command::Current(MilliAmps(25))]);
+        assert_eq!(session.responses.as_str(), "0.500\n0.025\n");
+    }
+
+    #[test]
+    fn headers_are_case_insensitive_in_short_and_long_form() {
+        assert_eq!(parse("volt 12"), Ok(Request::Command(Command::Voltage(MilliVolts(12_000)))));
+        assert_eq!(parse("Voltage 12"), Ok(Request::Command(Command::Voltage(MilliVolts(12_000)))));
+        assert_eq!(parse(":CURRent?"), Ok(Request::Query(Query::Current)));
+        assert_eq!(parse("meas:volt?"), Ok(Request::Query(Query::MeasuredVoltage)));
+        assert_eq!(parse("MEASure:VOLTage?"), Ok(Request::Query(Query::MeasuredVoltage)));
+        assert_eq!(parse("SYSTem:ERRor?"), Ok(Request::Query(Query::Error)));
+        assert_eq!(parse("  VOLT   7.5  "), Ok(Request::Command(Command::Voltage(MilliVolts(7500)))));
+    }
+
+    #[test]
+    fn output() {
+        let session = run(b"OUTP ON\nOUTP off\noutput 1\nOUTP 0\n");
+        assert_eq!(
+            session.commands.as_slice(),
+            &[Command::OutputOn, Command::OutputOff, Command::OutputOn, Command::OutputOff]
+        );
+        assert_eq!(parse("OUTP MAYBE"), Err(Error::IllegalParameterValue));
+    }
+
+    #[test]
+    fn output_query_follows_the_interlock() {
+        let mut state = State::new(false);
+        let mut remote = Remote::new();
+        assert_eq!(feed(&mut remote, &mut state, b"OUTP?\n").responses.as_str(), "0\n");
+        state.set_output_switch_state(true);
+        assert_eq!(feed(&mut remote, &mut state, b"OUTP?\n").responses.as_str(), "1\n");
+        assert_eq!(feed(&mut remote, &mut state, b"OUTP OFF\nOUTP?\n").responses.as_str(), "0\n");
+    }
+
+    #[test]
+    fn measured_voltage() {
+        let mut state = State::new(false);
+        state.set_filtered_voltage(4.9876);
+        let session = feed(&mut Remote::new(), &mut state, b"MEAS:VOLT?\n");
+        assert_eq!(session.responses.as_str(), "4.988\n");
+        state.set_filtered_voltage(-0.01);
+        let session = feed(&mut Remote::new(), &mut state, b"MEAS:VOLT?\n");
+        assert_eq!(session.responses.as_str(), "0.000\n");
+    }
+
+    #[test]
+    fn settings_use_the_keypad_limits() {
+        let session = run(b"VOLT 25\nVOLT 1\nCURR 3.001\nCURR -1\nVOLT?\n");
+        assert!(session.commands.is_empty());
+        assert_eq!(session.responses.as_str(), "1.291\n");
+        assert_eq!(parse("VOLT 20.241"), Ok(Request::Command(Command::Voltage(MilliVolts(20_241)))));
+        assert_eq!(parse("VOLT 1e10"), Err(Error::DataOutOfRange));
+        assert_eq!(parse("VOLT inf"), Err(Error::DataOutOfRange));
+        assert_eq!(parse("VOLT five"), Err(Error::NumericData));
+    }
+
+    #[test]
+    fn errors_are_queued_in_order() {
+        let session = run(b"FOO\nVOLT\nVOLT? 5\nVOLT 30\nSYST:ERR?\nSYST:ERR?\nSYST:ERR?\nSYST:ERR?\nSYST:ERR?\n");
+        assert_eq!(
+            session.responses.as_str(),
+            "-113,\"Undefined header\"\n\
+             -109,\"Missing parameter\"\n\
+             -108,\"Parameter not allowed\"\n\
+             -222,\"Data out of range\"\n\
+             0,\"No error\"\n"
+        );
+    }
+
+    #[test]
+    fn over_current_protection() {
+        let mut state = State::new(false);
+        state.set_output_switch_state(true);
+        state.set_current_limit(MilliAmps(500));
+        let mut remote = Remote::new();
+
+        let session = feed(
+            &mut remote,
+            &mut state,
+            b"CURR:PROT:DEL 0.002\nCURRent:PROTection:DELay?\n"
+        );
+        assert_eq!(session.commands.as_slice(), &[Command::OverCurrentDelay(2)]);
+        assert_eq!(session.responses.as_str(), "0.002\n");
+
+        state.set_measured_current(0.6);
+        state.update_protection(1);
+        assert_eq!(feed(&mut remote, &mut state, b"CURR:PROT:TRIP?\n").responses.as_str(), "0\n");
+        state.update_protection(1);
+        let session = feed(&mut remote, &mut state, b"CURR:PROT:TRIP?\nOUTP?\n");
+        assert_eq!(session.responses.as_str(), "1\n0\n");
+
+        let session = feed(
+            &mut remote,
+            &mut state,
+            b"OUTP:PROT:CLE\nCURR:PROT:TRIP?\nOUTP?\nOUTP ON\nOUTP?\n"
+        );
+        assert_eq!(session.commands.as_slice(), &[Command::ResetProtection, Command::OutputOn]);
+        assert_eq!(session.responses.as_str(), "0\n0\n1\n");
+    }
+
+    #[test]
+    fn over_voltage_protection() {
+        let mut state = State::new(false);
+        let mut remote = Remote::new();
+
+        let session = feed(&mut remote, &mut state, b"VOLT:PROT 12.5\nVOLT:PROT?\nVOLT?\n");
+        assert_eq!(session.commands.as_slice(), &[Command::OverVoltage(MilliVolts(12_500))]);
+        assert_eq!(session.responses.as_str(), "12.500\n1.291\n");
+
+        state.set_measured_voltage(13.);
+        state.update_protection(1);
+        let session = feed(&mut remote, &mut state, b"VOLT:PROT:TRIP?\nCURR:PROT:TRIP?\n");
+        assert_eq!(session.responses.as_str(), "1\n0\n");
+        assert_eq!(parse("VOLT:PROT 25.001"), Err(Error::DataOutOfRange));
+    }
+
+    #[test]
+    fn mode() {
+        let mut state = State::new(false);
+        state.set_output_switch_state(true);
+        state.set_current_limit(MilliAmps(500));
+        let mut remote = Remote::new();
+        let mut query = |state: &State| {
+            let mut response = Response::new();
+            assert_eq!(remote.execute("OUTP:MODE?", state, &mut response), None);
+            response
+        };
+        assert_eq!(query(&state).as_str(), "CV\n");
+        state.set_measured_current(0.5);
+        state.update_mode();
+        assert_eq!(query(&state).as_str(), "CC\n");
+        state.apply_command(Command::OutputOff);
+        assert_eq!(query(&state).as_str(), "OFF\n");
+        assert_eq!(parse("OUTP:MODE CC"), Err(Error::UndefinedHeader));
+    }
+
+    #[test]
+    fn slew_rate() {
+        let session = run(b"VOLT:SLEW 0.5\nVOLTage:SLEW?\nVOLT:SLEW 0\nVOLT:SLEW?\n");
+        assert_eq!(session.commands.as_slice(), &[Command::SlewRate(500)]);
+        assert_eq!(session.responses.as_str(), "0.500\n0.500\n");
+    }
+
+    #[test]
+    fn protection_headers() {
+        assert_eq!(parse("OUTP:PROT:CLE 1"), Err(Error::ParameterNotAllowed));
+        assert_eq!(parse("OUTP:PROT:CLE?"), Err(Error::UndefinedHeader));
+        assert_eq!(parse("CURR:PROT:TRIP 1"), Err(Error::UndefinedHeader));
+        assert_eq!(parse("CURR:PROT:DEL 11"), Err(Error::DataOutOfRange));
+        assert_eq!(parse("CURR:PROT?"), Err(Error::UndefinedHeader));
+    }
+
+    #[test]
+    fn unknown_headers() {
+        assert_eq!(parse("MEAS:CURR?"), Err(Error::UndefinedHeader));
+        assert_eq!(parse("MEAS:VOLT:DC?"), Err(Error::UndefinedHeader));
+        assert_eq!(parse("MEAS:VOLT 5"), Err(Error::UndefinedHeader));
+        assert_eq!(parse("VOLTS 5"), Err(Error::UndefinedHeader));
+        assert_eq!(parse("?"), Err(Error::UndefinedHeader));
+    }
+
+    #[test]
+    fn full_error_queue_reports_overflow() {
+        let mut state = State::new(false);
+        let mut remote = Remote::new();
+        for _ in 0..ERROR_QUEUE + 3 {
+            feed(&mut remote, &mut state, b"FOO\n");
+        }
+        assert_eq!(remote.error_count(), ERROR_QUEUE);
+        let mut last = ArrayString::<[u8; 512]>::new();
+        for _ in 0..ERROR_QUEUE {
+            last = feed(&mut remote, &mut state, b"SYST:ERR?\n").responses;
+        }
+        assert_eq!(last.as_str(), "-350,\"Queue overflow\"\n");
+        assert_eq!(remote.error_count(), 0);
+    }
+
+    #[test]
+    fn long_lines_are_dropped() {
+        let mut state = State::new(false);
+        let mut remote = Remote::new();
+        let mut long_line = [b' '; MAX_LINE + 10];
+        long_line[..8].copy_from_slice(b"VOLT 5.0");
+        *long_line.last_mut().unwrap() = b'\n';
+        let session = feed(&mut remote, &mut state, &long_line);
+        assert!(session.commands.is_empty());
+
+        // The next line works again
+        let session = feed(&mut remote, &mut state, b"VOLT 5.0\nSYST:ERR?\n");
+        assert_eq!(session.commands.as_slice(), &[Command::Voltage(MilliVolts(5000))]);
+        assert_eq!(session.responses.as_str(), "-363,\"Input buffer overrun\"\n");
+    }
+
+    #[test]
+    fn invalid_bytes_drop_the_line() {
+        let session = run(b"VOLT \xff5\nSYST:ERR?\n");
+        assert!(session.commands.is_empty());
+        assert_eq!(session.responses.as_str(), "-101,\"Invalid character\"\n");
+    }
+
+    #[test]
+    fn lines_with_receive_errors_are_dropped() {
+        let mut state = State::new(false);
+        let mut remote = Remote::new();
+        feed(&mut remote, &mut state, b"VOLT ");
+        // The "1" of "12.5" was lost
+        remote.discard_line(Error::FramingError);
+        remote.discard_line(Error::InputBufferOverrun);
+        let session = feed(&mut remote, &mut state, b"2.5\nVOLT?\nSYST:ERR?\nSYST:ERR?\n");
+        assert!(session.commands.is_empty());
+        assert_eq!(
+            session.responses.as_str(),
+            "1.291\n-362,\"Framing error in program message\"\n0,\"No error\"\n"
+        );
+
+        // An error between lines only drops the next one
+        remote.discard_line(Error::CommunicationError);
+        let session = feed(&mut remote, &mut state, b"VOLT 5\nVOLT 12.5\n");
+        assert_eq!(session.commands.as_slice(), &[Command::Voltage(MilliVolts(12_500))]);
+    }
+
+    fn drain(output: &mut OutputQueue) -> ArrayString<[u8; 512]> {
+        let mut sent = ArrayString::new();
+        while let Some(byte) = output.pop() {
+            sent.push(byte as char);
+        }
+        sent
+    }
+
+    #[test]
+    fn output_queue_keeps_the_order() {
+        let mut output = OutputQueue::new();
+        assert!(output.is_empty());
+        assert!(output.push("5.000\n"));
+        assert!(output.push("1\n"));
+        assert_eq!(output.front(), Some(b'5'));
+        assert_eq!(output.pop(), Some(b'5'));
+        assert!(output.push("CV\n"));
+        assert_eq!(drain(&mut output).as_str(), ".000\n1\nCV\n");
+        assert_eq!(output.front(), None);
+    }
+
+    #[test]
+    fn output_queue_wraps_around() {
+        let mut output = OutputQueue::new();
+        for _ in 0..OUTPUT_QUEUE {
+            assert!(output.push(IDENTITY));
+            assert_eq!(drain(&mut output).as_str(), IDENTITY);
+        }
+    }
+
+    #[test]
+    fn full_output_queue_drops_whole_responses() {
+        let mut output = OutputQueue::new();
+        let line = [b'a'; MAX_LINE];
+        let line = ::core::str::from_utf8(&line).unwrap();
+        for _ in 0..OUTPUT_QUEUE / MAX_LINE {
+            assert!(output.push(line));
+        }
+        assert!(!output.push("1\n"));
+        output.pop();
+        assert!(!output.push("1\n"));
+        output.pop();
+        assert!(output.push("1\n"));
+        assert!(drain(&mut output).ends_with("aa1\n"));
+    }
+
+    #[test]
+    fn empty_lines_are_ignored() {
+        let session = run(b"\n\r\n   \nSYST:ERR?\n");
+        assert_eq!(session.responses.as_str(), "0,\"No error\"\n");
+    }
+
+    #[test]
+    fn lines_can_arrive_in_pieces() {
+        let mut state = State::new(false);
+        let mut remote = Remote::new();
+        assert!(feed(&mut remote, &mut state, b"VO").commands.is_empty());
+        assert!(feed(&mut remote, &mut state, b"LT 3.").commands.is_empty());
+        let session = feed(&mut remote, &mut state, b"3\r\nVOLT?");
+        assert_eq!(session.commands.as_slice(), &[Command::Voltage(MilliVolts(3300))]);
+        assert_eq!(feed(&mut remote, &mut state, b"\n").responses.as_str(), "3.300\n");
+    }
+}
@@ -0,0 +1,238 @@
+/*!
+  Keypad scanning from a periodic timer interrupt.
+
+  The interrupt calls `Scanner::tick` which scans the keypad and pushes the
+  resulting key events into an `EventQueue`. The UI pops them from the other
+  end without having to lock out the interrupt, which is safe because there
+  is exactly one producer and one consumer.
+*/
+// See keypad.rs
+#![allow(deprecated)]
+
+use core::borrow::{Borrow, BorrowMut};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use hal::digital::{InputPin, OutputPin};
+
+use debounce::{Debouncer, EventKind, KeyEvent, Timing};
+use keypad::Keypad;
+
+/// The most events the queue can hold before new events are dropped
+pub const QUEUE_CAPACITY: usize = 16;
+// One slot is always left empty to tell a full queue from an empty one
+const QUEUE_SLOTS: usize = QUEUE_CAPACITY + 1;
+
+const EMPTY_EVENT: KeyEvent = KeyEvent { key: (0, 0), kind: EventKind::Press, time: 0 };
+
+/**
+  Fixed size single producer, single consumer queue of key events. Use
+  `split` to get the two ends.
+*/
+pub struct EventQueue {
+    buffer: UnsafeCell<[KeyEvent; QUEUE_SLOTS]>,
+    /// The next slot to read. Only written by the consumer
+    head: AtomicUsize,
+    /// The next slot to write. Only written by the producer
+    tail: AtomicUsize,
+}
+
+// The producer and consumer never access the same slot at the same time
+unsafe impl Sync for EventQueue {}
+
+impl EventQueue {
+    pub const fn new() -> Self {
+        Self {
+            buffer: UnsafeCell::new([EMPTY_EVENT; QUEUE_SLOTS]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn split(&mut self) -> (Producer<'_>, Consumer<'_>) {
+        (Producer { queue: self }, Consumer { queue: self })
+    }
+}
+
+impl Default for EventQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Producer<'a> {
+    queue: &'a EventQueue,
+}
+
+impl<'a> Producer<'a> {
+    /// Adds an event to the queue. Gives the event back if the queue is full
+    pub fn enqueue(&mut self, event: KeyEvent) -> Result<(), KeyEvent> {
+        let tail = self.queue.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % QUEUE_SLOTS;
+        if next == self.queue.head.load(Ordering::Acquire) {
+            return Err(event);
+        }
+        unsafe {
+            (*self.queue.buffer.get())[tail] = event;
+        }
+        self.queue.tail.store(next, Ordering::Release);
+        Ok(())
+    }
+}
+
+pub struct Consumer<'a> {
+    queue: &'a EventQueue,
+}
+
+impl<'a> Consumer<'a> {
+    /// Removes the oldest event from the queue
+    pub fn dequeue(&mut self) -> Option<KeyEvent> {
+        let head = self.queue.head.load(Ordering::Relaxed);
+        if head == self.queue.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let event = unsafe { (*self.queue.buffer.get())[head] };
+        self.queue.head.store((head + 1) % QUEUE_SLOTS, Ordering::Release);
+        Some(event)
+    }
+}
+
+
+/**
+  Debounces the keypad once per timer tick and queues the events.
+*/
+pub struct Scanner<'a> {
+    debouncer: Debouncer,
+    /// Milliseconds between ticks
+    period: u32,
+    now: u32,
+    events: Producer<'a>,
+    dropped: u32,
+}
+
+impl<'a> Scanner<'a> {
+    pub fn new(timing: Timing, period: u32, events: Producer<'a>) -> Self {
+        Self {
+            debouncer: Debouncer::new(timing),
+            period,
+            now: 0,
+            events,
+            dropped: 0,
+        }
+    }
+
+    /// Scans the keypad. Must be called every `period` milliseconds
+    pub fn tick<R, C, I, O>(&mut self, keypad: &mut Keypad<R, C, I, O>)
+    where R: Borrow<[I]>,
+          C: BorrowMut<[O]>,
+          I: InputPin,
+          O: OutputPin,
+    {
+        for &event in &self.debouncer.scan(keypad, self.now) {
+            if self.events.enqueue(event).is_err() {
+                self.dropped = self.dropped.wrapping_add(1);
+            }
+        }
+        self.now = self.now.wrapping_add(self.period);
+    }
+
+    /// How many events were lost because the queue was full
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use keypad::mock::{Matrix, keypad};
+
+    fn event(time: u32) -> KeyEvent {
+        KeyEvent { key: (1, 2), kind: EventKind::Press, time }
+    }
+
+    const TIMING: Timing = Timing {
+        debounce: 10,
+        long_press: 100,
+        repeat_delay: None,
+        repeat_interval: 0,
+    };
+
+    #[test]
+    fn queue_is_fifo() {
+        let mut queue = EventQueue::new();
+        let (mut producer, mut consumer) = queue.split();
+        assert_eq!(consumer.dequeue(), None);
+        producer.enqueue(event(1)).unwrap();
+        producer.enqueue(event(2)).unwrap();
+        assert_eq!(consumer.dequeue(), Some(event(1)));
+        producer.enqueue(event(3)).unwrap();
+        assert_eq!(consumer.dequeue(), Some(event(2)));
+        assert_eq!(consumer.dequeue(), Some(event(3)));
+        assert_eq!(consumer.dequeue(), None);
+    }
+
+    #[test]
+    fn full_queue_rejects_events() {
+        let mut queue = EventQueue::new();
+        let (mut producer, mut consumer) = queue.split();
+        for i in 0..QUEUE_CAPACITY as u32 {
+            producer.enqueue(event(i)).unwrap();
+        }
+        assert_eq!(producer.enqueue(event(100)), Err(event(100)));
+        assert_eq!(consumer.dequeue(), Some(event(0)));
+        producer.enqueue(event(100)).unwrap();
+    }
+
+    #[test]
+    fn queue_wraps_around() {
+        let mut queue = EventQueue::new();
+        let (mut producer, mut consumer) = queue.split();
+        for i in 0..(QUEUE_SLOTS * 3) as u32 {
+            producer.enqueue(event(i)).unwrap();
+            assert_eq!(consumer.dequeue(), Some(event(i)));
+        }
+        assert_eq!(consumer.dequeue(), None);
+    }
+
+    #[test]
+    fn scanner_queues_debounced_events() {
+        let matrix = Matrix::new(&[]);
+        let mut keypad = keypad(&matrix);
+        let mut queue = EventQueue::new();
+        let (producer, mut consumer) = queue.split();
+        let mut scanner = Scanner::new(TIMING, 5, producer);
+
+        for tick in 0..40 {
+            // Pressed from 20 ms to 100 ms
+            matrix.set_pressed(if (4..20).contains(&tick) { &[(2, 1)] } else { &[] });
+            scanner.tick(&mut keypad);
+        }
+
+        assert_eq!(consumer.dequeue(), Some(KeyEvent { key: (2, 1), kind: EventKind::Press, time: 30 }));
+        assert_eq!(
+            consumer.dequeue(),
+            Some(KeyEvent { key: (2, 1), kind: EventKind::Release { held: 80 }, time: 110 })
+        );
+        assert_eq!(consumer.dequeue(), None);
+        assert_eq!(scanner.dropped(), 0);
+    }
+
+    #[test]
+    fn scanner_counts_dropped_events() {
+        let matrix = Matrix::new(&[]);
+        let mut keypad = keypad(&matrix);
+        let mut queue = EventQueue::new();
+        let (producer, _consumer) = queue.split();
+        let mut scanner = Scanner::new(TIMING, 5, producer);
+
+        for tick in 0..200 {
+            matrix.set_pressed(if (tick / 4) % 2 == 0 { &[(0, 0)] } else { &[] });
+            scanner.tick(&mut keypad);
+        }
+        // 25 presses and releases, of which only QUEUE_CAPACITY fit
+        assert_eq!(scanner.dropped(), 50 - QUEUE_CAPACITY as u32);
+    }
+}
@@ -0,0 +1,994 @@
+use arrayvec::{ArrayString, CapacityError};
+use itoa;
+
+use interface::Command;
+use calibration::{Calibration, CalibrationSession};
+use persistence::Settings;
+use protection::{Fault, Protection, DEFAULT_OVER_VOLTAGE};
+use ramp::Ramp;
+use readout::{Reading, Readout};
+use regulation::{Mode, ModeDetector};
+use units::{MilliAmps, MilliVolts};
+use voltage::MIN_VOLTAGE;
+
+/// How many presets can be stored. Slots are numbered from 1
+pub const PRESET_SLOTS: usize = 4;
+
+/// Setpoints stored in a preset slot
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Preset {
+    pub voltage: MilliVolts,
+    pub current_limit: MilliAmps,
+}
+
+impl Preset {
+    /// Writes the setpoints the way the status line shows them, "12.50V 0.25A"
+    pub fn push_display(&self, result: &mut ArrayString<[u8; 32]>) {
+        push_milli_units(result, self.voltage.0, "V ");
+        push_milli_units(result, self.current_limit.0, "A");
+    }
+}
+
+/// What the status line shows
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum View {
+    /// The setpoints and whether the output is on
+    Setpoints,
+    /// The measured voltage, current and power
+    Measurements,
+}
+
+/**
+  The state of the supply.
+
+  The output is live only when all three of these hold:
+
+  - The hardware interlock is armed. If the output switch is already on at
+    power up, the interlock stays disarmed until the switch has been turned
+    off once, so the supply never comes up with a live output.
+  - The physical output switch on PA8 is on.
+  - The output is enabled in software through `Command::OutputOn`. Software
+    enable defaults to on so that the switch alone controls the output until
+    the keypad turns it off.
+
+  A protection trip turns software enable off, and the latched fault keeps
+  the output off until it is reset. `Command::OutputOn` is ignored until then.
+  Resetting doesn't turn the output back on into the load that tripped it,
+  that takes another `Command::OutputOn`.
+
+  The control loop follows `ramped_voltage` rather than the setpoint, which
+  moves towards `output_voltage` at the slew rate. Turning the output on
+  ramps it up from 0 V.
+
+  While the output is live, the status line shows whether it regulates the
+  voltage (CV) or the current (CC) instead of "On". The measurement view has
+  no room for that next to the power, so it only shows "Off" or "Dis" in
+  place of the power while the output is off.
+*/
+pub struct State {
+    set_voltage: MilliVolts,
+    /// Over-voltage threshold
+    over_voltage: MilliVolts,
+    current_limit: MilliAmps,
+    /// The latest voltage sample as it is, for the over-voltage protection
+    measured_voltage: f32,
+    /// The voltage after the measurement filter
+    filtered_voltage: f32,
+    /// After the measurement filter
+    measured_current: f32,
+    output_switch_state: bool,
+    interlock_armed: bool,
+    software_enabled: bool,
+    calibration: Calibration,
+    calibration_session: Option<CalibrationSession>,
+    presets: [Option<Preset>; PRESET_SLOTS],
+    protection: Protection,
+    ramp: Ramp,
+    regulation: ModeDetector,
+    view: View,
+    readout: Readout,
+}
+
+impl State {
+    pub fn new(output_switch_state: bool) -> Self {
+        Self {
+            set_voltage: MIN_VOLTAGE,
+            over_voltage: DEFAULT_OVER_VOLTAGE,
+            current_limit: MilliAmps(0),
+            measured_voltage: 0.,
+            filtered_voltage: 0.,
+            measured_current: 0.,
+            output_switch_state,
+            interlock_armed: !output_switch_state,
+            software_enabled: true,
+            calibration: Calibration::default(),
+            calibration_session: None,
+            presets: [None; PRESET_SLOTS],
+            protection: Protection::default(),
+            ramp: Ramp::default(),
+            regulation: ModeDetector::default(),
+            view: View::Setpoints,
+            readout: Readout::new(),
+        }
+    }
+
+
+    pub fn output_voltage(&self) -> MilliVolts {
+        if self.output_enabled() {
+            self.set_voltage
+        }
+        else {
+            MilliVolts(0)
+        }
+    }
+
+    /**
+      Returns true if the output is live according to the model described on
+      `State`
+    */
+    pub fn output_enabled(&self) -> bool {
+        self.interlock_armed
+            && self.output_switch_state
+            && self.software_enabled
+            && self.protection.fault().is_none()
+    }
+
+    pub fn set_output_switch_state(&mut self, new: bool) {
+        self.output_switch_state = new;
+        if !new {
+            self.interlock_armed = true;
+        }
+    }
+
+    /// Whether the output has been turned on, the interlock and protection aside
+    pub fn software_enabled(&self) -> bool {
+        self.software_enabled
+    }
+
+    pub fn set_software_enabled(&mut self, enabled: bool) {
+        self.software_enabled = enabled;
+    }
+
+    pub fn apply_command(&mut self, command: Command) {
+        match command {
+            Command::Voltage(val) => self.set_voltage(val),
+            Command::Current(val) => self.set_current_limit(val),
+            // Turning the output on does nothing until a fault is reset
+            Command::OutputOn => {
+                if self.protection.fault().is_none() {
+                    self.set_software_enabled(true)
+                }
+            }
+            Command::OutputOff => self.set_software_enabled(false),
+            Command::CalibrationDuty(duty) => {
+                match self.calibration_session {
+                    Some(ref mut session) => session.set_duty(duty),
+                    None => self.calibration_session = Some(CalibrationSession::new(duty)),
+                }
+            }
+            Command::CalibrationMeasured(voltage) => {
+                if let Some(ref mut session) = self.calibration_session {
+                    session.record(voltage.volts());
+                }
+            }
+            Command::CalibrationDone => {
+                // Keep the old calibration if the new points don't make sense
+                if let Some(session) = self.calibration_session.take() {
+                    if let Ok(calibration) = session.finish() {
+                        self.calibration = calibration;
+                    }
+                }
+            }
+            Command::StorePreset(slot) => {
+                let preset = Preset {
+                    voltage: self.set_voltage,
+                    current_limit: self.current_limit,
+                };
+                if let Some(stored) = preset_index(slot).map(|i| &mut self.presets[i]) {
+                    *stored = Some(preset);
+                }
+            }
+            Command::RecallPreset(slot) => {
+                if let Some(preset) = self.preset(slot) {
+                    self.set_voltage(preset.voltage);
+                    self.set_current_limit(preset.current_limit);
+                }
+            }
+            Command::ResetProtection => self.protection.reset(),
+            Command::OverCurrentDelay(delay) => self.protection.set_over_current_delay(delay),
+            Command::OverVoltage(threshold) => self.over_voltage = threshold,
+            Command::SlewRate(rate) => self.ramp.set_rate(rate),
+            Command::ToggleView => {
+                self.view = match self.view {
+                    View::Setpoints => View::Measurements,
+                    View::Measurements => View::Setpoints,
+                }
+            }
+        }
+    }
+
+    /// The preset in `slot`, or None if the slot is empty or doesn't exist
+    pub fn preset(&self, slot: u8) -> Option<Preset> {
+        preset_index(slot).and_then(|i| self.presets[i])
+    }
+
+    /**
+      Describes what `command` would change, for the interface to show before
+      or after sending it. `Ok(None)` if there is nothing worth showing and
+      `Err` with the reason if the command would do nothing.
+    */
+    pub fn preview(&self, command: &Command) -> Result<Option<ArrayString<[u8; 32]>>, &'static str> {
+        match *command {
+            Command::RecallPreset(slot) => {
+                let preset = self.preset(slot).ok_or("Empty slot")?;
+                let mut result = ArrayString::new();
+                preset.push_display(&mut result);
+                Ok(Some(result))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The parts of the state which are kept across power cycles
+    pub fn settings(&self) -> Settings {
+        Settings {
+            voltage: self.set_voltage,
+            over_voltage: self.over_voltage,
+            current_limit: self.current_limit,
+            over_current_delay: self.protection.over_current_delay(),
+            calibration: self.calibration.clone(),
+            presets: self.presets,
+        }
+    }
+
+    /// Applies loaded settings, clamped to the limits they are entered with
+    pub fn apply_settings(&mut self, mut settings: Settings) {
+        settings.clamp_to_limits();
+        self.set_voltage = settings.voltage;
+        self.over_voltage = settings.over_voltage;
+        self.current_limit = settings.current_limit;
+        self.protection.set_over_current_delay(settings.over_current_delay);
+        self.calibration = settings.calibration;
+        self.presets = settings.presets;
+    }
+
+    pub fn calibration(&self) -> &Calibration {
+        &self.calibration
+    }
+
+    /**
+      The duty to output open loop while calibrating, or None if no
+      calibration is in progress
+    */
+    pub fn calibration_duty(&self) -> Option<f32> {
+        self.calibration_session.as_ref().map(|session| session.duty())
+    }
+
+    /// The voltage setpoint, also when the output is off
+    pub fn voltage(&self) -> MilliVolts {
+        self.set_voltage
+    }
+
+    pub fn set_voltage(&mut self, voltage: MilliVolts) {
+        self.set_voltage = voltage;
+    }
+
+    pub fn current_limit(&self) -> MilliAmps {
+        self.current_limit
+    }
+
+    pub fn set_current_limit(&mut self, current: MilliAmps) {
+        self.current_limit = current;
+    }
+
+    pub fn measured_voltage(&self) -> f32 {
+        self.measured_voltage
+    }
+
+    pub fn set_measured_voltage(&mut self, voltage: f32) {
+        self.measured_voltage = voltage;
+    }
+
+    /**
+      The measured voltage with the noise filtered out, which is what the
+      readout and the remote port show. Filtering delays the measurement, so
+      the protection uses `measured_voltage` instead
+    */
+    pub fn filtered_voltage(&self) -> f32 {
+        self.filtered_voltage
+    }
+
+    pub fn set_filtered_voltage(&mut self, voltage: f32) {
+        self.filtered_voltage = voltage;
+    }
+
+    pub fn measured_current(&self) -> f32 {
+        self.measured_current
+    }
+
+    pub fn set_measured_current(&mut self, current: f32) {
+        self.measured_current = current;
+    }
+
+    /**
+      Moves the ramped voltage `elapsed` ms towards the output voltage. Turning
+      the output off drops it to 0 V at once
+    */
+    pub fn update_ramp(&mut self, elapsed: u32) -> f32 {
+        if self.output_enabled() {
+            let target = self.output_voltage().volts();
+            self.ramp.step(target, elapsed)
+        }
+        else {
+            self.ramp.reset(0.);
+            0.
+        }
+    }
+
+    /// The voltage the control loop should aim for right now
+    pub fn ramped_voltage(&self) -> f32 {
+        self.ramp.value()
+    }
+
+    /// In mV/ms
+    pub fn slew_rate(&self) -> u32 {
+        self.ramp.rate()
+    }
+
+    pub fn over_voltage(&self) -> MilliVolts {
+        self.over_voltage
+    }
+
+    /**
+      Checks the latest measurements, which were taken `elapsed` ms after the
+      previous ones. Returns the fault if one tripped, which turns the output
+      off in software as well.
+
+      The voltage is checked even when the output is off, since a failed output
+      stage can produce a voltage anyway.
+    */
+    pub fn update_protection(&mut self, elapsed: u32) -> Option<Fault> {
+        let threshold = self.over_voltage.volts();
+        let mut fault = self.protection.check_voltage(self.measured_voltage, threshold);
+        if fault.is_none() && self.output_enabled() {
+            let limit = self.current_limit.amps();
+            fault = self.protection.check_current(self.measured_current, limit, elapsed);
+        }
+        if fault.is_some() {
+            self.software_enabled = false;
+        }
+        fault
+    }
+
+    /**
+      Updates the regulation mode from the latest measurements. Returns the new
+      mode if it changed, so the status line can be redrawn
+    */
+    pub fn update_mode(&mut self) -> Option<Mode> {
+        let previous = self.regulation.mode();
+        if !self.output_enabled() {
+            self.regulation.reset();
+            return None;
+        }
+        let mode = self.regulation.update(self.measured_current, self.current_limit.amps());
+        if mode != previous {
+            Some(mode)
+        }
+        else {
+            None
+        }
+    }
+
+    /// The regulation mode, `None` while the output is off
+    pub fn mode(&self) -> Option<Mode> {
+        if self.output_enabled() {
+            Some(self.regulation.mode())
+        }
+        else {
+            None
+        }
+    }
+
+    /**
+      Adds the latest measurements, taken `elapsed` ms after the previous ones,
+      to the readout. Returns true when the status line shows the measurements
+      and should be redrawn
+    */
+    pub fn update_readout(&mut self, elapsed: u32) -> bool {
+        let refreshed = self.readout.add(self.filtered_voltage, self.measured_current, elapsed);
+        refreshed && self.view == View::Measurements
+    }
+
+    /// Measurements averaged over the last refresh period
+    pub fn reading(&self) -> Reading {
+        self.readout.reading()
+    }
+
+    pub fn view(&self) -> View {
+        self.view
+    }
+
+    pub fn protection(&self) -> &Protection {
+        &self.protection
+    }
+
+    /// The status line in the selected view
+    pub fn status_line(&self) -> Result<ArrayString<[u8; 32]>, CapacityError<&str>> {
+        match self.view {
+            View::Setpoints => self.get_display(),
+            View::Measurements => {
+                let mut result = ArrayString::new();
+                let reading = self.readout.reading();
+                match self.protection.fault() {
+                    Some(fault) => result.push_str(fault.message()),
+                    None if self.output_enabled() => reading.push_display(&mut result),
+                    None => {
+                        reading.push_voltage_and_current(&mut result);
+                        result.push_str(self.output_label());
+                    }
+                }
+                Ok(result)
+            }
+        }
+    }
+
+    pub fn get_display(&self) -> Result<ArrayString<[u8; 32]>, CapacityError<&str>> {
+        let mut result = ArrayString::new();
+        if let Some(fault) = self.protection.fault() {
+            result.push_str(fault.message());
+            return Ok(result);
+        }
+
+        push_milli_units(&mut result, self.set_voltage.0, "V ");
+        push_milli_units(&mut result, self.current_limit.0, "A ");
+        result.push_str(self.output_label());
+
+        Ok(result)
+    }
+
+    /// "Dis" until the interlock is armed, the mode while live, else "Off"
+    fn output_label(&self) -> &'static str {
+        if !self.interlock_armed {
+            "Dis"
+        }
+        else if let Some(mode) = self.mode() {
+            mode.label()
+        }
+        else {
+            "Off"
+        }
+    }
+}
+
+fn preset_index(slot: u8) -> Option<usize> {
+    match slot as usize {
+        1..=PRESET_SLOTS => Some(slot as usize - 1),
+        _ => None,
+    }
+}
+
+/**
+  Pushes a value given in thousandths as a number with two decimals followed by
+  the unit. The status line only has room for 16 characters, so 12345 mV is
+  shown as "12.34V"
+*/
+pub fn push_milli_units(result: &mut ArrayString<[u8; 32]>, value: u32, unit: &str) {
+    let mut buffer = itoa::Buffer::new();
+    result.push_str(buffer.format(value / 1000));
+    result.push('.');
+    let hundredths = (value % 1000) / 10;
+    if hundredths < 10 {
+        result.push('0');
+    }
+    result.push_str(buffer.format(hundredths));
+    result.push_str(unit);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use arrayvec::ArrayVec;
+
+    use current::MAX_CURRENT_MA;
+    use protection::DEFAULT_OVER_CURRENT_DELAY;
+    use ramp::DEFAULT_SLEW_RATE;
+    use voltage::MAX_VOLTAGE;
+    use readout::REFRESH_PERIOD;
+
+    /**
+      Builds a state with the interlock in the requested state and the switch and
+      software enable set to the given values
+    */
+    fn state_with(armed: bool, switch: bool, software: bool) -> State {
+        let mut state = State::new(!armed);
+        state.set_output_switch_state(switch);
+        state.set_software_enabled(software);
+        state.set_voltage(MilliVolts(5000));
+        state
+    }
+
+    #[test]
+    fn output_enable_truth_table() {
+        for &armed in &[false, true] {
+            for &switch in &[false, true] {
+                for &software in &[false, true] {
+                    // Turning the switch off always arms the interlock
+                    let armed = armed || !switch;
+                    let state = state_with(armed, switch, software);
+                    let expected = armed && switch && software;
+                    assert_eq!(
+                        state.output_enabled(),
+                        expected,
+                        "armed: {}, switch: {}, software: {}", armed, switch, software
+                    );
+                    assert_eq!(state.output_voltage(), MilliVolts(if expected {5000} else {0}));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn switch_on_at_power_up_disables_output() {
+        let mut state = State::new(true);
+        assert!(!state.output_enabled());
+        assert!(state.get_display().unwrap().ends_with("Dis"));
+
+        state.set_output_switch_state(false);
+        assert!(!state.output_enabled());
+        assert!(state.get_display().unwrap().ends_with("Off"));
+
+        state.set_output_switch_state(true);
+        assert!(state.output_enabled());
+        assert!(state.get_display().unwrap().ends_with("CV"));
+    }
+
+    #[test]
+    fn switch_off_at_power_up_arms_interlock() {
+        let mut state = State::new(false);
+        assert!(!state.output_enabled());
+        state.set_output_switch_state(true);
+        assert!(state.output_enabled());
+    }
+
+    #[test]
+    fn keypad_commands_toggle_software_enable() {
+        let mut state = State::new(false);
+        state.set_output_switch_state(true);
+
+        state.apply_command(Command::OutputOff);
+        assert!(!state.output_enabled());
+        assert!(state.get_display().unwrap().ends_with("Off"));
+
+        state.apply_command(Command::OutputOn);
+        assert!(state.output_enabled());
+    }
+
+    #[test]
+    fn keypad_cannot_bypass_interlock() {
+        let mut state = State::new(true);
+        state.apply_command(Command::OutputOn);
+        assert!(!state.output_enabled());
+    }
+
+    #[test]
+    fn software_enable_survives_switch_toggle() {
+        let mut state = State::new(false);
+        state.apply_command(Command::OutputOff);
+        state.set_output_switch_state(true);
+        assert!(!state.output_enabled());
+        state.set_output_switch_state(false);
+        state.set_output_switch_state(true);
+        assert!(!state.output_enabled());
+    }
+
+    #[test]
+    fn setpoint_commands() {
+        let mut state = State::new(false);
+        state.apply_command(Command::Voltage(MilliVolts(12_500)));
+        state.apply_command(Command::Current(MilliAmps(250)));
+        assert_eq!(state.get_display().unwrap().as_str(), "12.50V 0.25A Off");
+        assert_eq!(state.current_limit(), MilliAmps(250));
+    }
+
+    #[test]
+    fn calibration_procedure() {
+        let mut state = State::new(false);
+        assert_eq!(state.calibration_duty(), None);
+
+        state.apply_command(Command::CalibrationDuty(0.2));
+        assert_eq!(state.calibration_duty(), Some(0.2));
+        state.apply_command(Command::CalibrationMeasured(MilliVolts(4000)));
+        state.apply_command(Command::CalibrationDuty(0.8));
+        state.apply_command(Command::CalibrationMeasured(MilliVolts(16_000)));
+        state.apply_command(Command::CalibrationDone);
+
+        assert_eq!(state.calibration_duty(), None);
+        assert_eq!(state.calibration().duty_for_voltage(10.), 0.5);
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let mut state = State::new(false);
+        state.apply_command(Command::Voltage(MilliVolts(3300)));
+        state.apply_command(Command::Current(MilliAmps(1500)));
+        state.apply_command(Command::StorePreset(2));
+        state.apply_command(Command::OverCurrentDelay(50));
+        let settings = state.settings();
+
+        let mut restored = State::new(false);
+        restored.apply_settings(settings.clone());
+        assert_eq!(restored.settings(), settings);
+        assert_eq!(restored.protection().over_current_delay(), 50);
+        assert_eq!(restored.get_display().unwrap().as_str(), "3.30V 1.50A Off");
+    }
+
+    #[test]
+    fn applied_settings_are_clamped() {
+        let mut state = State::new(false);
+        state.apply_settings(Settings {
+            voltage: MilliVolts(40_000),
+            current_limit: MilliAmps(9_000),
+            ..Settings::default()
+        });
+        assert_eq!(state.voltage(), MAX_VOLTAGE);
+        assert_eq!(state.current_limit(), MAX_CURRENT_MA);
+    }
+
+    /// An enabled output at 5 V with a 1 A limit
+    fn enabled_state() -> State {
+        let mut state = state_with(true, true, true);
+        state.set_current_limit(MilliAmps(1000));
+        state
+    }
+
+    /// Feeds measured currents one ms apart, returns when the output tripped
+    fn run_current_trace(state: &mut State, trace: &[f32]) -> Option<usize> {
+        trace.iter().position(|&current| {
+            state.set_measured_current(current);
+            state.update_protection(1).is_some()
+        })
+    }
+
+    #[test]
+    fn over_current_latches_the_output_off() {
+        let mut state = enabled_state();
+        let delay = DEFAULT_OVER_CURRENT_DELAY as usize;
+        let mut trace = [0.5; 40];
+        for current in &mut trace[10..] {
+            *current = 1.2;
+        }
+        assert_eq!(run_current_trace(&mut state, &trace), Some(10 + delay - 1));
+        assert!(!state.output_enabled());
+        assert_eq!(state.output_voltage(), MilliVolts(0));
+        assert_eq!(state.get_display().unwrap().as_str(), "OCP TRIP");
+
+        // Neither the current going away nor turning the output on clears it
+        assert_eq!(run_current_trace(&mut state, &[0.; 20]), None);
+        state.apply_command(Command::OutputOff);
+        state.apply_command(Command::OutputOn);
+        assert!(!state.output_enabled());
+
+        // Resetting re-arms the output without turning it on
+        state.apply_command(Command::ResetProtection);
+        assert!(!state.output_enabled());
+        assert_eq!(state.get_display().unwrap().as_str(), "5.00V 1.00A Off");
+        state.apply_command(Command::OutputOn);
+        assert!(state.output_enabled());
+        assert_eq!(state.get_display().unwrap().as_str(), "5.00V 1.00A CV");
+    }
+
+    #[test]
+    fn over_current_delay_is_configurable() {
+        let mut state = enabled_state();
+        state.apply_command(Command::OverCurrentDelay(100));
+        assert_eq!(state.protection().over_current_delay(), 100);
+        assert_eq!(run_current_trace(&mut state, &[1.5; 200]), Some(99));
+    }
+
+    #[test]
+    fn zero_current_limit_does_not_trip_on_noise() {
+        let mut state = state_with(true, true, true);
+        assert_eq!(state.current_limit(), MilliAmps(0));
+        let noise = [0.003, 0.001, 0.005, 0.002, 0.004];
+        let mut trace = [0.; 200];
+        for (current, noise) in trace.iter_mut().zip(noise.iter().cycle()) {
+            *current = *noise;
+        }
+        assert_eq!(run_current_trace(&mut state, &trace), None);
+        assert!(state.output_enabled());
+    }
+
+    #[test]
+    fn disabled_output_does_not_trip() {
+        let mut state = enabled_state();
+        state.apply_command(Command::OutputOff);
+        assert_eq!(run_current_trace(&mut state, &[2.; 100]), None);
+        state.apply_command(Command::OutputOn);
+        assert!(run_current_trace(&mut state, &[2.; 100]).is_some());
+    }
+
+    /**
+      First order model of the output stage, which produces `gain` times the
+      voltage the calibration expects for a duty
+    */
+    struct Plant {
+        voltage: f32,
+        gain: f32,
+    }
+
+    impl Plant {
+        fn step(&mut self, duty: f32, calibration: &Calibration) {
+            let min_voltage = calibration.voltage_for_duty(0.);
+            let steady_state = min_voltage + (calibration.voltage_for_duty(duty) - min_voltage) * self.gain;
+            // 10 ms time constant, stepped every ms
+            self.voltage += (steady_state - self.voltage) * 0.1;
+        }
+    }
+
+    /**
+      Runs the supply open loop for `steps` ms. `duty` gives the duty the
+      output stage actually produces from the duty it was asked for. Returns
+      when protection tripped and the highest voltage seen
+    */
+    fn run_plant<F>(state: &mut State, plant: &mut Plant, steps: usize, duty: F) -> (Option<usize>, f32)
+    where F: Fn(f32) -> f32
+    {
+        let mut trip = None;
+        let mut highest = plant.voltage;
+        for step in 0..steps {
+            let requested = if state.output_enabled() {
+                state.calibration().duty_for_voltage(state.output_voltage().volts())
+            }
+            else {
+                0.
+            };
+            plant.step(duty(requested), &state.calibration().clone());
+            highest = highest.max(plant.voltage);
+            state.set_measured_voltage(plant.voltage);
+            if state.update_protection(1).is_some() {
+                trip = Some(step);
+            }
+        }
+        (trip, highest)
+    }
+
+    fn over_voltage_state() -> State {
+        let mut state = state_with(true, true, true);
+        state.apply_command(Command::OverVoltage(MilliVolts(6000)));
+        state
+    }
+
+    #[test]
+    fn healthy_plant_does_not_trip() {
+        let mut state = over_voltage_state();
+        let mut plant = Plant { voltage: 0., gain: 1. };
+        let (trip, highest) = run_plant(&mut state, &mut plant, 200, |duty| duty);
+        assert_eq!(trip, None);
+        assert!((highest - 5.).abs() < 0.01, "{}", highest);
+    }
+
+    #[test]
+    fn drifted_calibration_trips_over_voltage() {
+        let mut state = over_voltage_state();
+        let mut plant = Plant { voltage: 0., gain: 1.5 };
+        let (trip, highest) = run_plant(&mut state, &mut plant, 200, |duty| duty);
+        assert!(trip.is_some());
+        // Tripped on the first sample above the threshold
+        assert!(highest < 6.5, "{}", highest);
+        assert!(!state.output_enabled());
+        assert_eq!(state.get_display().unwrap().as_str(), "OVP TRIP");
+        // With the output off the voltage decays and the fault stays latched
+        assert!(plant.voltage < 2.);
+        assert_eq!(state.protection().fault(), Some(Fault::OverVoltage));
+    }
+
+    #[test]
+    fn stuck_output_stage_trips_with_output_off() {
+        let mut state = over_voltage_state();
+        state.apply_command(Command::OutputOff);
+        let mut plant = Plant { voltage: 0., gain: 1. };
+        let (trip, _) = run_plant(&mut state, &mut plant, 200, |_| 1.);
+        assert!(trip.is_some());
+        assert_eq!(state.protection().fault(), Some(Fault::OverVoltage));
+
+        // Resetting doesn't help while the voltage is still too high
+        state.apply_command(Command::ResetProtection);
+        state.apply_command(Command::OutputOn);
+        let (trip, _) = run_plant(&mut state, &mut plant, 1, |_| 1.);
+        assert_eq!(trip, Some(0));
+    }
+
+    /// Ramps for `steps` ms and returns the ramped voltages
+    fn ramp_trace(state: &mut State, steps: usize) -> ArrayVec<[f32; 1024]> {
+        (0..steps).map(|_| state.update_ramp(1)).collect()
+    }
+
+    fn is_monotonic(trace: &[f32], rising: bool) -> bool {
+        trace.windows(2).all(|pair| if rising { pair[0] <= pair[1] } else { pair[0] >= pair[1] })
+    }
+
+    #[test]
+    fn enabling_the_output_ramps_up() {
+        let mut state = state_with(true, true, false);
+        state.update_ramp(1);
+        state.apply_command(Command::OutputOn);
+        let trace = ramp_trace(&mut state, 600);
+        assert!(is_monotonic(&trace[..], true));
+        assert_eq!(trace[0], DEFAULT_SLEW_RATE as f32 / 1000.);
+        assert!(trace[498] < 5.);
+        assert_eq!(trace[499], 5.);
+        assert_eq!(*trace.last().unwrap(), 5.);
+    }
+
+    #[test]
+    fn setpoint_changes_are_slew_rate_limited() {
+        let mut state = state_with(true, true, true);
+        state.apply_command(Command::SlewRate(100));
+        assert_eq!(state.slew_rate(), 100);
+        ramp_trace(&mut state, 100);
+        assert_eq!(state.ramped_voltage(), 5.);
+
+        state.apply_command(Command::Voltage(MilliVolts(12_000)));
+        let trace = ramp_trace(&mut state, 100);
+        assert!(is_monotonic(&trace[..], true));
+        assert_eq!(state.ramped_voltage(), 12.);
+
+        state.apply_command(Command::Voltage(MilliVolts(3300)));
+        let trace = ramp_trace(&mut state, 100);
+        assert!(is_monotonic(&trace[..], false));
+        assert!(trace[10] > 10.);
+        assert_eq!(state.ramped_voltage(), 3.3);
+    }
+
+    #[test]
+    fn turning_the_output_off_drops_at_once() {
+        let mut state = state_with(true, true, true);
+        ramp_trace(&mut state, 1000);
+        state.set_output_switch_state(false);
+        assert_eq!(state.update_ramp(1), 0.);
+        state.set_output_switch_state(true);
+        assert!(state.update_ramp(1) < 0.1);
+    }
+
+    #[test]
+    fn mode_follows_the_measured_current() {
+        let mut state = enabled_state();
+        assert_eq!(state.mode(), Some(Mode::ConstantVoltage));
+        assert_eq!(state.update_mode(), None);
+
+        state.set_measured_current(0.99);
+        assert_eq!(state.update_mode(), Some(Mode::ConstantCurrent));
+        assert_eq!(state.update_mode(), None);
+        assert_eq!(state.get_display().unwrap().as_str(), "5.00V 1.00A CC");
+
+        // Within the hysteresis band
+        state.set_measured_current(0.96);
+        assert_eq!(state.update_mode(), None);
+        state.set_measured_current(0.9);
+        assert_eq!(state.update_mode(), Some(Mode::ConstantVoltage));
+        assert_eq!(state.get_display().unwrap().as_str(), "5.00V 1.00A CV");
+    }
+
+    #[test]
+    fn mode_is_cleared_with_the_output_off() {
+        let mut state = enabled_state();
+        state.set_measured_current(1.);
+        state.update_mode();
+        state.apply_command(Command::OutputOff);
+        assert_eq!(state.mode(), None);
+        assert_eq!(state.update_mode(), None);
+        assert!(state.get_display().unwrap().ends_with("Off"));
+
+        // Comes back in CV until the current says otherwise
+        state.set_measured_current(0.);
+        state.apply_command(Command::OutputOn);
+        assert_eq!(state.mode(), Some(Mode::ConstantVoltage));
+    }
+
+    #[test]
+    fn measurement_view() {
+        let mut state = enabled_state();
+        state.set_filtered_voltage(4.98);
+        state.set_measured_current(0.5);
+        // Not redrawn while the setpoints are shown
+        assert!(!state.update_readout(REFRESH_PERIOD));
+        assert_eq!(state.status_line().unwrap().as_str(), "5.00V 1.00A CV");
+
+        state.apply_command(Command::ToggleView);
+        assert_eq!(state.view(), View::Measurements);
+        assert_eq!(state.status_line().unwrap().as_str(), "4.98V 0.50A 2.5W");
+        assert!(!state.update_readout(REFRESH_PERIOD - 1));
+        assert!(state.update_readout(1));
+
+        state.apply_command(Command::ToggleView);
+        assert_eq!(state.status_line().unwrap(), state.get_display().unwrap());
+    }
+
+    #[test]
+    fn measurement_view_shows_when_the_output_is_off() {
+        let mut state = enabled_state();
+        state.apply_command(Command::ToggleView);
+        state.apply_command(Command::OutputOff);
+        assert_eq!(state.status_line().unwrap().as_str(), "0.00V 0.00A Off");
+
+        let mut state = State::new(true);
+        state.apply_command(Command::ToggleView);
+        state.set_filtered_voltage(20.);
+        state.set_measured_current(3.);
+        state.update_readout(REFRESH_PERIOD);
+        assert_eq!(state.status_line().unwrap().as_str(), "20.00V 3.00A Dis");
+    }
+
+    #[test]
+    fn measurement_view_shows_faults() {
+        let mut state = enabled_state();
+        state.apply_command(Command::ToggleView);
+        state.set_measured_voltage(25.);
+        state.update_protection(1);
+        assert_eq!(state.status_line().unwrap().as_str(), "OVP TRIP");
+    }
+
+    #[test]
+    fn presets() {
+        let mut state = State::new(false);
+        state.apply_command(Command::Voltage(MilliVolts(3300)));
+        state.apply_command(Command::Current(MilliAmps(500)));
+        state.apply_command(Command::StorePreset(1));
+        state.apply_command(Command::Voltage(MilliVolts(12_000)));
+        state.apply_command(Command::Current(MilliAmps(2000)));
+        state.apply_command(Command::StorePreset(4));
+        assert_eq!(state.preset(1), Some(Preset { voltage: MilliVolts(3300), current_limit: MilliAmps(500) }));
+        assert_eq!(state.preset(2), None);
+
+        state.apply_command(Command::RecallPreset(1));
+        assert_eq!(state.get_display().unwrap().as_str(), "3.30V 0.50A Off");
+        state.apply_command(Command::RecallPreset(4));
+        assert_eq!(state.get_display().unwrap().as_str(), "12.00V 2.00A Off");
+    }
+
+    #[test]
+    fn missing_presets_are_ignored() {
+        let mut state = State::new(false);
+        state.apply_command(Command::Voltage(MilliVolts(5000)));
+        state.apply_command(Command::StorePreset(0));
+        state.apply_command(Command::StorePreset(PRESET_SLOTS as u8 + 1));
+        state.apply_command(Command::RecallPreset(2));
+        assert_eq!(state.preset(0), None);
+        assert_eq!(state.preset(PRESET_SLOTS as u8 + 1), None);
+        assert_eq!(state.set_voltage, MilliVolts(5000));
+    }
+
+    #[test]
+    fn recall_preview() {
+        let mut state = State::new(false);
+        state.apply_command(Command::Voltage(MilliVolts(5000)));
+        state.apply_command(Command::Current(MilliAmps(500)));
+        state.apply_command(Command::StorePreset(3));
+        assert_eq!(
+            state.preview(&Command::RecallPreset(3)).unwrap().unwrap().as_str(),
+            "5.00V 0.50A"
+        );
+        assert_eq!(state.preview(&Command::RecallPreset(1)), Err("Empty slot"));
+        assert_eq!(state.preview(&Command::OutputOn), Ok(None));
+    }
+
+    #[test]
+    fn preset_display() {
+        let mut result = ArrayString::new();
+        Preset { voltage: MilliVolts(12_500), current_limit: MilliAmps(250) }.push_display(&mut result);
+        assert_eq!(result.as_str(), "12.50V 0.25A");
+    }
+
+    #[test]
+    fn failed_calibration_keeps_old_table() {
+        let mut state = State::new(false);
+        state.apply_command(Command::CalibrationDuty(0.2));
+        state.apply_command(Command::CalibrationMeasured(MilliVolts(4000)));
+        state.apply_command(Command::CalibrationDone);
+
+        assert_eq!(state.calibration_duty(), None);
+        assert_eq!(state.calibration(), &Calibration::default());
+    }
+}
@@ -0,0 +1,135 @@
+/*!
+  Fixed point voltages and currents for setpoints.
+
+  Setpoints are typed in whole mV and mA, so they are kept as integers and
+  come back exactly as they were entered. Floating point is only used where a
+  setpoint meets the analog side, in the control loop and the calibration.
+*/
+
+/**
+  Rounds a value in whole units to the nearest thousandth. Returns `None` for
+  values that are negative, not a number or too large
+*/
+pub fn thousandths(value: f32) -> Option<u32> {
+    let thousandths = value * 1000. + 0.5;
+    // The largest u32 rounds up to 2^32 as an f32
+    if (0. ..4_294_967_296.).contains(&thousandths) {
+        Some(thousandths as u32)
+    }
+    else {
+        None
+    }
+}
+
+macro_rules! milli_unit {
+    ($(#[$attr:meta])* $name:ident, $unit:ident, $from_unit:ident) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+        pub struct $name(pub u32);
+
+        impl $name {
+            /// Converts a value in whole units, see [`thousandths`](fn.thousandths.html)
+            pub fn $from_unit(value: f32) -> Option<Self> {
+                thousandths(value).map($name)
+            }
+
+            /// The value in whole units
+            pub fn $unit(self) -> f32 {
+                self.0 as f32 / 1000.
+            }
+
+            pub fn checked_add(self, other: Self) -> Option<Self> {
+                self.0.checked_add(other.0).map($name)
+            }
+
+            pub fn checked_sub(self, other: Self) -> Option<Self> {
+                self.0.checked_sub(other.0).map($name)
+            }
+
+            pub fn checked_mul(self, factor: u32) -> Option<Self> {
+                self.0.checked_mul(factor).map($name)
+            }
+
+            pub fn checked_div(self, divisor: u32) -> Option<Self> {
+                self.0.checked_div(divisor).map($name)
+            }
+
+            /// Whole units and the thousandths after the decimal point
+            pub fn split(self) -> (u32, u32) {
+                (self.0 / 1000, self.0 % 1000)
+            }
+        }
+
+        impl From<$name> for u32 {
+            fn from(value: $name) -> u32 {
+                value.0
+            }
+        }
+    };
+}
+
+milli_unit!(
+    /// A voltage in mV
+    MilliVolts, volts, from_volts
+);
+
+milli_unit!(
+    /// A current in mA
+    MilliAmps, amps, from_amps
+);
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_millivolt_survives_whole_units() {
+        for millivolts in 0..=25_000 {
+            let voltage = MilliVolts(millivolts);
+            assert_eq!(MilliVolts::from_volts(voltage.volts()), Some(voltage));
+        }
+    }
+
+    #[test]
+    fn every_milliamp_survives_whole_units() {
+        for milliamps in 0..=10_000 {
+            let current = MilliAmps(milliamps);
+            assert_eq!(MilliAmps::from_amps(current.amps()), Some(current));
+        }
+    }
+
+    #[test]
+    fn conversion_rounds() {
+        assert_eq!(MilliVolts::from_volts(1.234), Some(MilliVolts(1234)));
+        assert_eq!(MilliVolts::from_volts(1.2345), Some(MilliVolts(1235)));
+        assert_eq!(MilliAmps::from_amps(0.0004), Some(MilliAmps(0)));
+        assert_eq!(MilliAmps::from_amps(2.9996), Some(MilliAmps(3000)));
+    }
+
+    #[test]
+    fn unrepresentable_values() {
+        assert_eq!(MilliVolts::from_volts(-0.01), None);
+        assert_eq!(MilliVolts::from_volts(5e6), None);
+        assert_eq!(MilliVolts::from_volts(f32::NAN), None);
+        assert_eq!(MilliAmps::from_amps(f32::INFINITY), None);
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        let max = MilliVolts(u32::MAX);
+        assert_eq!(MilliVolts(5000).checked_add(MilliVolts(12)), Some(MilliVolts(5012)));
+        assert_eq!(max.checked_add(MilliVolts(1)), None);
+        assert_eq!(MilliVolts(1291).checked_sub(MilliVolts(1292)), None);
+        assert_eq!(MilliAmps(250).checked_mul(4), Some(MilliAmps(1000)));
+        assert_eq!(MilliAmps(u32::MAX / 2 + 1).checked_mul(2), None);
+        assert_eq!(MilliAmps(1000).checked_div(3), Some(MilliAmps(333)));
+        assert_eq!(MilliAmps(1000).checked_div(0), None);
+    }
+
+    #[test]
+    fn split() {
+        assert_eq!(MilliVolts(12345).split(), (12, 345));
+        assert_eq!(MilliAmps(50).split(), (0, 50));
+    }
+}
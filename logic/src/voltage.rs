@@ -0,0 +1,47 @@
+use units::MilliVolts;
+
+/// The output voltage which makes the voltage sense divider produce a full scale ADC reading
+pub const MEASUREMENT_FULL_SCALE: f32 = 3.3 * (10. + 1.5) / 1.5;
+
+/**
+  Range of the voltage setpoint. The output can't go below the voltage at 0%
+  duty or above the voltage at 100% duty
+*/
+pub const MIN_VOLTAGE: MilliVolts = MilliVolts(1291);
+pub const MAX_VOLTAGE: MilliVolts = MilliVolts(20241);
+
+/**
+  Returns how far `target` is from `min_voltage` towards `max_voltage`, as a
+  fraction. Targets below `min_voltage` give 0, and an empty range gives `None`
+*/
+pub fn pwm_percentage_for_voltage(
+    target: MilliVolts,
+    min_voltage: MilliVolts,
+    max_voltage: MilliVolts
+) -> Option<f32> {
+    let span = max_voltage.checked_sub(min_voltage).filter(|span| span.0 > 0)?;
+    let offset = target.checked_sub(min_voltage).unwrap_or_default();
+    Some(offset.0 as f32 / span.0 as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentage_spans_range() {
+        let (min, max) = (MilliVolts(1000), MilliVolts(3000));
+        assert_eq!(pwm_percentage_for_voltage(MilliVolts(1000), min, max), Some(0.));
+        assert_eq!(pwm_percentage_for_voltage(MilliVolts(2000), min, max), Some(0.5));
+        assert_eq!(pwm_percentage_for_voltage(MilliVolts(3000), min, max), Some(1.));
+    }
+
+    #[test]
+    fn percentage_outside_range() {
+        let (min, max) = (MilliVolts(1000), MilliVolts(3000));
+        assert_eq!(pwm_percentage_for_voltage(MilliVolts(500), min, max), Some(0.));
+        assert_eq!(pwm_percentage_for_voltage(MilliVolts(4000), min, max), Some(1.5));
+        assert_eq!(pwm_percentage_for_voltage(MilliVolts(2000), max, min), None);
+        assert_eq!(pwm_percentage_for_voltage(MilliVolts(2000), min, min), None);
+    }
+}
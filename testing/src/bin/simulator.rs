@@ -0,0 +1,122 @@
+/*!
+  Host side simulator of the front panel.
+
+  Each line read from stdin is treated as a sequence of key presses on the
+  keypad. Characters produced by long presses, like `.`, can be typed
+  directly. Characters that are not on the keypad are ignored, except for `s`
+  which flips the output switch and `q` which quits. After every line the two
+  LCD lines and the PWM duty cycles are printed.
+*/
+extern crate logic;
+
+use std::io::{self, BufRead, Write};
+
+use logic::{current, interface, keymap, state};
+use logic::menu::{Menu, LINE_LENGTH};
+
+struct Simulator {
+    interface_state: Menu,
+    state: state::State,
+    output_switch: bool,
+}
+
+impl Simulator {
+    fn new() -> Self {
+        Self {
+            interface_state: Menu::new(&interface::MAIN_MENU),
+            state: state::State::new(false),
+            output_switch: false,
+        }
+    }
+
+    fn press(&mut self, key: char) {
+        let input = match interface::DEFAULT_KEY_BINDINGS.input(key) {
+            Some(input) => input,
+            None => return,
+        };
+        let interface_state = std::mem::replace(&mut self.interface_state, Menu::new(&interface::MAIN_MENU));
+        let (new_state, command) = interface_state.update(input, &self.state);
+        self.interface_state = new_state;
+
+        if let Some(command) = command {
+            println!("Command: {:?}", command);
+            self.state.apply_command(command);
+        }
+    }
+
+    fn toggle_switch(&mut self) {
+        self.output_switch = !self.output_switch;
+        self.state.set_output_switch_state(self.output_switch);
+    }
+
+    fn render(&self) {
+        let top = self.interface_state.get_display().unwrap();
+        let bottom = self.state.status_line().unwrap();
+
+        let duty_fraction = if !self.state.output_enabled() {
+            0.
+        }
+        else {
+            self.state.calibration_duty().unwrap_or_else(|| {
+                self.state.calibration().duty_for_voltage(self.state.output_voltage().volts())
+            })
+        };
+        let current_fraction = current::pwm_percentage_for_current(
+            self.state.current_limit().amps(),
+            current::MAX_CURRENT
+        );
+
+        println!("+{}+", "-".repeat(LINE_LENGTH));
+        println!("|{}|", lcd_line(&top));
+        println!("|{}|", lcd_line(&bottom));
+        println!("+{}+", "-".repeat(LINE_LENGTH));
+        println!(
+            "Voltage duty: {:.1}%  Current duty: {:.1}%  Switch: {}",
+            clamp_duty(duty_fraction) * 100.,
+            clamp_duty(current_fraction) * 100.,
+            if self.output_switch {"on"} else {"off"}
+        );
+    }
+}
+
+/**
+  Pads or truncates a message to the width of the LCD, the same way
+  `write_line` does on the controller
+*/
+fn lcd_line(message: &str) -> String {
+    let message = &message[..message.len().min(LINE_LENGTH)];
+    format!("{:<width$}", message, width = LINE_LENGTH)
+}
+
+/// The PWM peripheral saturates the duty to the range it can produce
+fn clamp_duty(fraction: f32) -> f32 {
+    fraction.clamp(0., 1.)
+}
+
+fn is_key(input: char) -> bool {
+    keymap::KEYMAP.contains(&input) || keymap::LONG_PRESS_KEYMAP.contains(&Some(input))
+}
+
+fn main() {
+    let mut simulator = Simulator::new();
+    println!("Keys: 0-9, a (enter), b (backspace), c (clear), x (cancel), '.'.");
+    println!("'s' flips the output switch, 'q' quits");
+    simulator.render();
+
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line.expect("Failed to read from stdin");
+
+        for input in line.chars() {
+            match input {
+                'q' => return,
+                's' => simulator.toggle_switch(),
+                _ if is_key(input) => simulator.press(input),
+                _ => {}
+            }
+        }
+
+        simulator.render();
+        io::stdout().flush().unwrap();
+    }
+}
@@ -1 +0,0 @@
-../../controller/src/interface.rs
\ No newline at end of file
@@ -1,4 +0,0 @@
-extern crate arrayvec;
-extern crate itoa;
-
-mod interface;